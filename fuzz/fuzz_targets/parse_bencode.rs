@@ -0,0 +1,23 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// downpour is a binary crate, so the bencode module is pulled in directly from its source
+#[path = "../../src"]
+#[allow(dead_code, unused_imports)]
+mod src {
+    pub mod bencode;
+}
+
+use src::bencode::{self, BencodeValue, ParseOptions};
+
+fuzz_target!(|data: &[u8]| {
+    // Whatever the input, parsing must return rather than panic or overflow the stack
+    let _ = bencode::decode(data, &ParseOptions::default());
+
+    if let Ok(value) = bencode::decode(data, &ParseOptions::strict()) {
+        // Strict mode only accepts canonical input, which the encoder must reproduce byte for byte
+        let value = BencodeValue::from(value);
+        assert_eq!(bencode::encode_bencode(&value), data);
+    }
+});
@@ -0,0 +1,395 @@
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use rand::seq::SliceRandom;
+use reqwest::Url;
+use tokio::{
+    sync::{mpsc, watch, Notify},
+    task::JoinHandle,
+    time::Instant,
+};
+
+use crate::{
+    dht::Dht,
+    metainfo::Sha1Hash,
+    peer_list::{self, AnnounceEvent, AnnounceRequest, AnnounceResponse},
+    ClientConfig,
+};
+
+// Used when a tracker doesn't tell us how often to announce
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+// We never announce to the same tracker more often than this, whatever it tells us
+const DEFAULT_MIN_INTERVAL: Duration = Duration::from_secs(60);
+// How long we wait to try a tracker again after a failed announce
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+// How often we look the torrent up on the DHT again. Announced peers expire after 30 minutes.
+const DHT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+// The counters reported to trackers, as kept up to date by the downloader. All counts are in bytes.
+pub struct TransferStats {
+    pub uploaded: AtomicU64,
+    pub downloaded: AtomicU64,
+    pub left: AtomicU64,
+    // Set once every piece has been downloaded
+    pub complete: watch::Sender<bool>,
+    // Notified whenever the downloader runs out of peers to connect to
+    pub peers_wanted: Notify,
+}
+
+impl TransferStats {
+    pub fn new(left: u64) -> Self {
+        Self {
+            uploaded: AtomicU64::new(0),
+            downloaded: AtomicU64::new(0),
+            left: AtomicU64::new(left),
+            complete: watch::channel(false).0,
+            peers_wanted: Notify::new(),
+        }
+    }
+}
+
+struct Tracker {
+    url: Url,
+    // Whether the tracker has accepted our started event, and so knows about us
+    started: bool,
+    // The ID the tracker last gave us, if any
+    tracker_id: Option<Vec<u8>>,
+}
+
+impl Tracker {
+    fn new(url: Url) -> Self {
+        Self {
+            url,
+            started: false,
+            tracker_id: None,
+        }
+    }
+
+    async fn announce(
+        &mut self,
+        event: AnnounceEvent,
+        info_hash: &Sha1Hash,
+        stats: &TransferStats,
+        client_config: &ClientConfig,
+    ) -> Result<AnnounceResponse> {
+        // A tracker that doesn't know about us yet has to be sent our started event first, whatever else is happening
+        let event = match event {
+            AnnounceEvent::Regular | AnnounceEvent::Completed if !self.started => AnnounceEvent::Started,
+            _ => event,
+        };
+
+        let request = AnnounceRequest {
+            info_hash,
+            event,
+            uploaded: stats.uploaded.load(Ordering::Relaxed),
+            downloaded: stats.downloaded.load(Ordering::Relaxed),
+            left: stats.left.load(Ordering::Relaxed),
+            tracker_id: self.tracker_id.as_deref(),
+        };
+
+        let response = peer_list::announce(&self.url, &request, client_config).await?;
+        if event == AnnounceEvent::Started {
+            self.started = true;
+        }
+
+        if let Some(warning) = &response.warning {
+            eprintln!("WARNING: Tracker {} says: {}", self.url, warning);
+        }
+        if let (Some(seeders), Some(leechers)) = (response.seeders, response.leechers) {
+            println!("Tracker {} reports {} seeders and {} leechers", self.url, seeders, leechers);
+        }
+        // Trackers need only send their ID once, after which we keep sending back the last one we got
+        if response.tracker_id.is_some() {
+            self.tracker_id = response.tracker_id.clone();
+        }
+
+        Ok(response)
+    }
+}
+
+// A list of tiers of trackers, announced to as laid out in BEP 12: the tiers are tried in order, as are the trackers
+// within each, until one responds. That tracker is then moved to the front of its tier, so it's tried first next time.
+struct TrackerTiers {
+    tiers: Vec<Vec<Tracker>>,
+    // Whether any tracker has accepted our started event
+    started: bool,
+    // Whether we've completed the download, and have yet to have a tracker accept our completed event
+    completed_pending: bool,
+    // As given by the last tracker to respond
+    interval: Duration,
+    min_interval: Duration,
+    last_announce: Option<Instant>,
+    next_announce: Instant,
+}
+
+impl TrackerTiers {
+    fn new(tiers: Vec<Vec<Tracker>>) -> Self {
+        Self {
+            tiers,
+            started: false,
+            completed_pending: false,
+            interval: DEFAULT_INTERVAL,
+            min_interval: DEFAULT_MIN_INTERVAL,
+            last_announce: None,
+            next_announce: Instant::now(),
+        }
+    }
+
+    // Returns the peers given to us by the first tracker to respond, or None if none of them did
+    async fn announce(
+        &mut self,
+        event: AnnounceEvent,
+        info_hash: &Sha1Hash,
+        stats: &TransferStats,
+        client_config: &ClientConfig,
+    ) -> Option<HashSet<SocketAddr>> {
+        let now = Instant::now();
+        self.last_announce = Some(now);
+
+        for tier in &mut self.tiers {
+            for i in 0..tier.len() {
+                // A tracker that hasn't had our started event is sent that instead
+                let sends_completed = event == AnnounceEvent::Completed && tier[i].started;
+
+                match tier[i].announce(event, info_hash, stats, client_config).await {
+                    Ok(response) => {
+                        tier[..=i].rotate_right(1);
+
+                        self.min_interval = response.min_interval.unwrap_or_default().max(DEFAULT_MIN_INTERVAL);
+                        self.interval = response.interval.unwrap_or(DEFAULT_INTERVAL).max(self.min_interval);
+                        self.next_announce = now + self.interval;
+
+                        self.started = true;
+                        if sends_completed {
+                            self.completed_pending = false;
+                        }
+
+                        return Some(response.peers);
+                    }
+                    Err(e) => eprintln!("WARNING: Failed to announce to tracker {}: {}", tier[i].url, e),
+                }
+            }
+        }
+
+        self.next_announce = now + RETRY_INTERVAL;
+        None
+    }
+
+    // Announces whenever it's due, until told to stop
+    async fn run(
+        mut self,
+        info_hash: Sha1Hash,
+        stats: Arc<TransferStats>,
+        client_config: ClientConfig,
+        peer_tx: mpsc::UnboundedSender<SocketAddr>,
+        mut stop_rx: watch::Receiver<bool>,
+    ) {
+        let mut complete_rx = stats.complete.subscribe();
+
+        loop {
+            let was_started = self.started;
+
+            let event = tokio::select! {
+                _ = stop_rx.changed() => break,
+                // Our completed event is retried until a tracker accepts it
+                _ = tokio::time::sleep_until(self.next_announce) => match self.completed_pending {
+                    true => AnnounceEvent::Completed,
+                    false => AnnounceEvent::Regular,
+                },
+                // A torrent that was already complete when we started never gets a completed event
+                Ok(()) = complete_rx.changed(), if self.started => {
+                    if !*complete_rx.borrow() {
+                        continue;
+                    }
+                    self.completed_pending = true;
+                    AnnounceEvent::Completed
+                },
+                _ = stats.peers_wanted.notified() => {
+                    // Ask for more peers as soon as the tracker allows
+                    if let Some(last_announce) = self.last_announce {
+                        self.next_announce = self.next_announce.min(last_announce + self.min_interval);
+                    }
+                    continue;
+                },
+            };
+
+            if let Some(peers) = self.announce(event, &info_hash, &stats, &client_config).await {
+                for peer in peers {
+                    // The downloader going away means we're about to be stopped anyway
+                    let _ = peer_tx.send(peer);
+                }
+            }
+
+            // Our started event told the tracker how much we had left, so it already knows if we'd completed by then
+            if !was_started && self.started {
+                complete_rx.borrow_and_update();
+            }
+        }
+
+        self.stop(&info_hash, &stats, &client_config).await;
+    }
+
+    // Sends our stopped event to every tracker that knows about us
+    async fn stop(&mut self, info_hash: &Sha1Hash, stats: &TransferStats, client_config: &ClientConfig) {
+        let stops = self
+            .tiers
+            .iter_mut()
+            .flatten()
+            .filter(|tracker| tracker.started)
+            .map(|tracker| async move {
+                if let Err(e) = tracker.announce(AnnounceEvent::Stopped, info_hash, stats, client_config).await {
+                    eprintln!("WARNING: Failed to announce to tracker {}: {}", tracker.url, e);
+                }
+            });
+
+        futures::future::join_all(stops).await;
+    }
+}
+
+// Announces the torrent to its trackers, and to the DHT if we're using it, for as long as we're running.
+// The trackers are worked through tier by tier, unless we've been told to announce to all of them at once,
+// in which case each is announced to on its own schedule, as given by the interval in its responses.
+pub struct Announcer {
+    tracker_groups: Vec<TrackerTiers>,
+    dht: Option<Dht>,
+    info_hash: Sha1Hash,
+    stats: Arc<TransferStats>,
+    client_config: ClientConfig,
+}
+
+impl Announcer {
+    pub fn new(
+        announce_list: &[Vec<Url>],
+        dht: Option<Dht>,
+        info_hash: Sha1Hash,
+        stats: Arc<TransferStats>,
+        client_config: ClientConfig,
+    ) -> Self {
+        let mut tiers: Vec<Vec<Tracker>> = announce_list
+            .iter()
+            .map(|tier| tier.iter().cloned().map(Tracker::new).collect())
+            .filter(|tier: &Vec<Tracker>| !tier.is_empty())
+            .collect();
+
+        for tier in &mut tiers {
+            tier.shuffle(&mut rand::thread_rng());
+        }
+
+        let tracker_groups = match client_config.announce_to_all {
+            true => tiers.into_iter().flatten().map(|tracker| TrackerTiers::new(vec![vec![tracker]])).collect(),
+            false if tiers.is_empty() => Vec::new(),
+            false => vec![TrackerTiers::new(tiers)],
+        };
+
+        Self {
+            tracker_groups,
+            dht,
+            info_hash,
+            stats,
+            client_config,
+        }
+    }
+
+    // Sends our started event to the trackers, and looks the torrent up on the DHT, returning all the peers found
+    pub async fn announce_started(&mut self) -> HashSet<SocketAddr> {
+        let Self {
+            tracker_groups,
+            dht,
+            info_hash,
+            stats,
+            client_config,
+        } = self;
+
+        let tracker_announces = tracker_groups
+            .iter_mut()
+            .map(|group| group.announce(AnnounceEvent::Started, info_hash, stats, client_config));
+
+        let dht_peers = async {
+            match dht {
+                Some(dht) => peer_list::fetch_peers_from_dht(dht, info_hash, client_config).await,
+                None => HashSet::new(),
+            }
+        };
+
+        let (tracker_peer_sets, mut peers) = futures::join!(futures::future::join_all(tracker_announces), dht_peers);
+        peers.extend(tracker_peer_sets.into_iter().flatten().flatten());
+
+        peers
+    }
+
+    // Stops using the DHT, shutting our node down if nothing else is using it
+    pub fn disable_dht(&mut self) {
+        self.dht = None;
+    }
+
+    // Keeps announcing in the background, sending any peers found down peer_tx
+    pub fn spawn(self, peer_tx: mpsc::UnboundedSender<SocketAddr>) -> RunningAnnouncer {
+        let (stop_tx, stop_rx) = watch::channel(false);
+
+        let mut tasks: Vec<JoinHandle<()>> = self
+            .tracker_groups
+            .into_iter()
+            .map(|group| {
+                tokio::spawn(group.run(
+                    self.info_hash,
+                    self.stats.clone(),
+                    self.client_config.clone(),
+                    peer_tx.clone(),
+                    stop_rx.clone(),
+                ))
+            })
+            .collect();
+
+        if let Some(dht) = self.dht {
+            tasks.push(tokio::spawn(Self::run_dht(
+                dht,
+                self.info_hash,
+                self.client_config,
+                peer_tx,
+                stop_rx,
+            )));
+        }
+
+        RunningAnnouncer { stop_tx, tasks }
+    }
+
+    async fn run_dht(
+        dht: Dht,
+        info_hash: Sha1Hash,
+        client_config: ClientConfig,
+        peer_tx: mpsc::UnboundedSender<SocketAddr>,
+        mut stop_rx: watch::Receiver<bool>,
+    ) {
+        loop {
+            tokio::select! {
+                _ = stop_rx.changed() => break,
+                _ = tokio::time::sleep(DHT_ANNOUNCE_INTERVAL) => {},
+            }
+
+            for peer in peer_list::fetch_peers_from_dht(&dht, &info_hash, &client_config).await {
+                let _ = peer_tx.send(peer);
+            }
+        }
+    }
+}
+
+pub struct RunningAnnouncer {
+    stop_tx: watch::Sender<bool>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl RunningAnnouncer {
+    // Sends our stopped event to every tracker that knows about us
+    pub async fn stop(self) {
+        self.stop_tx.send_replace(true);
+        futures::future::join_all(self.tasks).await;
+    }
+}
@@ -1,17 +1,75 @@
-use std::collections::HashMap;
+use std::{cell::Cell, collections::HashMap};
 
 use nom::{
     branch::alt,
     character::complete::{char, digit1},
-    combinator::{map, map_res},
-    sequence::{delimited, tuple},
-    IResult, InputTakeAtPosition, multi::many0, bytes::complete::{take, take_until}, error::ErrorKind, AsChar
+    combinator::{cut, map, recognize},
+    sequence::terminated,
+    IResult, InputTakeAtPosition, multi::many0, bytes::complete::take, error::ErrorKind, AsChar
 };
 
-use anyhow::{anyhow, Result};
+mod de;
+mod error;
+mod ser;
+
+pub use de::{from_bytes, from_ref};
+pub use error::{BencodeError, BencodeErrorKind, ParseError};
+pub use ser::to_bytes;
 
 type BencodeBytes = Vec<u8>;
 
+pub type ParseResult<'a, T> = IResult<&'a [u8], T, ParseError<'a>>;
+
+// The default limits are intended for untrusted input, e.g. tracker responses or messages from peers.
+// They're generous enough for any reasonable metainfo file, but can be raised for trusted input.
+#[derive(Debug, Clone)]
+pub struct ParseOptions {
+    // Reject anything not in canonical form: integers with leading zeros or a negative zero,
+    // and dictionaries whose keys are unsorted or duplicated
+    pub strict: bool,
+    // The maximum number of lists and dictionaries that may be nested inside one another
+    pub max_depth: usize,
+    // The maximum number of values (of any type, at any depth) in the input
+    pub max_elements: usize,
+    // The maximum length of any one byte string
+    pub max_string_length: usize,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self {
+            strict: false,
+            max_depth: 64,
+            max_elements: 1 << 21,
+            max_string_length: 1 << 28,
+        }
+    }
+}
+
+impl ParseOptions {
+    pub fn strict() -> Self {
+        Self {
+            strict: true,
+            ..Self::default()
+        }
+    }
+}
+
+// Per-parse state shared by every level of the recursive descent
+struct ParseState<'o> {
+    options: &'o ParseOptions,
+    elements: Cell<usize>,
+}
+
+impl<'o> ParseState<'o> {
+    fn new(options: &'o ParseOptions) -> Self {
+        Self {
+            options,
+            elements: Cell::new(0),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum BencodeValue {
     Bytes(BencodeBytes),
@@ -20,124 +78,366 @@ pub enum BencodeValue {
     Dictionary(HashMap<BencodeBytes, BencodeValue>),
 }
 
-impl BencodeValue {
-    pub fn as_bytes(&self) -> Result<&BencodeBytes> {
-        if let BencodeValue::Bytes(bytes) = self  {
-            Ok(bytes)
-        } else {
-            Err(anyhow!("Expected byte string, found {}", format!("{:?}", self)))
+// A borrowed counterpart to BencodeValue. Byte strings are slices of the parsed input rather than copies of it,
+// and dictionaries keep their entries in the order they appeared (including any duplicated keys).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BencodeRef<'a> {
+    Bytes(&'a [u8]),
+    Integer(i64),
+    List(Vec<BencodeRef<'a>>),
+    Dictionary(Vec<(&'a [u8], BencodeRef<'a>)>),
+}
+
+impl<'a> From<BencodeRef<'a>> for BencodeValue {
+    fn from(value: BencodeRef<'a>) -> Self {
+        match value {
+            BencodeRef::Bytes(bytes) => BencodeValue::Bytes(bytes.to_vec()),
+            BencodeRef::Integer(int) => BencodeValue::Integer(int),
+            BencodeRef::List(list) => BencodeValue::List(list.into_iter().map(BencodeValue::from).collect()),
+            // If a key is duplicated, the last occurrence wins
+            BencodeRef::Dictionary(pairs) => BencodeValue::Dictionary(
+                pairs.into_iter()
+                    .map(|(key, value)| (key.to_vec(), value.into()))
+                    .collect()
+            ),
         }
     }
+}
+
+fn failure(input: &[u8], kind: BencodeErrorKind) -> nom::Err<ParseError<'_>> {
+    nom::Err::Failure(ParseError::new(input, kind))
+}
 
-    pub fn as_integer(&self) -> Result<i64> {
-        if let BencodeValue::Integer(int) = self {
-            Ok(*int)
-        } else {
-            Err(anyhow!("Expected integer, found {}", format!("{:?}", self)))
+fn digit1_or_negative(input: &[u8]) -> ParseResult<'_, &[u8]> {
+    input.split_at_position1_complete(|item| !(item.is_dec_digit() || item == b'-'), ErrorKind::Digit)
+}
+
+fn parse_digits<'a, T: std::str::FromStr>(digits: &[u8], input: &'a [u8]) -> Result<T, nom::Err<ParseError<'a>>> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse().ok())
+        .ok_or_else(|| failure(input, BencodeErrorKind::InvalidInteger))
+}
+
+fn parse_integer<'a>(input: &'a [u8], state: &ParseState) -> ParseResult<'a, i64> {
+    let (digits_start, _) = char('i')(input)?;
+
+    // Once we've seen the 'i' this can't be any other kind of value, so any error from here on is fatal
+    let (remaining, digits) = cut(digit1_or_negative)(digits_start)?;
+
+    if state.options.strict {
+        match digits {
+            b"-0" => return Err(failure(digits_start, BencodeErrorKind::NegativeZero)),
+            [b'0', _, ..] | [b'-', b'0', ..] => return Err(failure(digits_start, BencodeErrorKind::LeadingZero)),
+            _ => {}
         }
     }
 
-    pub fn as_list(&self) -> Result<&Vec<BencodeValue>> {
-        if let BencodeValue::List(list) = self {
-            Ok(list)
-        } else {
-            Err(anyhow!("Expected list, found {}", format!("{:?}", self)))
-        }
+    let int = parse_digits(digits, digits_start)?;
+    let (remaining, _) = cut(char('e'))(remaining)?;
+
+    Ok((remaining, int))
+}
+
+// Lists and dictionaries are parsed recursively, so their nesting has to be bounded to protect the stack
+fn check_depth<'a>(input: &'a [u8], state: &ParseState, depth: usize) -> Result<(), nom::Err<ParseError<'a>>> {
+    if depth >= state.options.max_depth {
+        Err(failure(input, BencodeErrorKind::DepthLimitExceeded))
+    } else {
+        Ok(())
     }
+}
+
+fn parse_list<'a>(input: &'a [u8], state: &ParseState, depth: usize) -> ParseResult<'a, Vec<BencodeRef<'a>>> {
+    let (items_start, _) = char('l')(input)?;
+    check_depth(input, state, depth)?;
 
-    pub fn as_dict(&self) -> Result<&HashMap<BencodeBytes, BencodeValue>> {
-        if let BencodeValue::Dictionary(dict) = self {
-            Ok(dict)
-        } else {
-            Err(anyhow!("Expected dictionary, found {}", format!("{:?}", self)))
+    cut(terminated(
+        many0(|i| parse_value(i, state, depth + 1)),
+        char('e')
+    ))(items_start)
+}
+
+// Parses the key-value pairs of a dictionary in the order they appear, using parse_value for the values
+fn parse_dictionary<'a, T>(
+    input: &'a [u8],
+    state: &ParseState,
+    depth: usize,
+    mut parse_value: impl FnMut(&'a [u8]) -> ParseResult<'a, T>,
+) -> ParseResult<'a, Vec<(&'a [u8], T)>> {
+    let (mut remaining, _) = char('d')(input)?;
+    check_depth(input, state, depth)?;
+
+    let mut pairs: Vec<(&[u8], T)> = Vec::new();
+
+    loop {
+        if let Ok((rest, _)) = char::<_, ParseError>('e')(remaining) {
+            return Ok((rest, pairs));
         }
+
+        let (rest, key) = cut(|i| parse_bytes(i, state))(remaining)?;
+
+        if state.options.strict {
+            if let Some((previous_key, _)) = pairs.last() {
+                if key == *previous_key {
+                    return Err(failure(remaining, BencodeErrorKind::DuplicateKey));
+                } else if key < *previous_key {
+                    return Err(failure(remaining, BencodeErrorKind::UnsortedKey));
+                }
+            }
+        }
+
+        let (rest, value) = cut(&mut parse_value)(rest)?;
+        pairs.push((key, value));
+        remaining = rest;
+    }
+}
+
+fn parse_bytes<'a>(input: &'a [u8], state: &ParseState) -> ParseResult<'a, &'a [u8]> {
+    let (remaining, length_digits) = digit1(input)?;
+
+    if state.options.strict && length_digits.len() > 1 && length_digits[0] == b'0' {
+        return Err(failure(input, BencodeErrorKind::LeadingZero));
     }
 
-    pub fn as_str(&self) -> Result<&str> {
-        let self_bytes = self.as_bytes()?;
-        Ok(std::str::from_utf8(self_bytes)?)
+    let byte_string_len: u64 = parse_digits(length_digits, input)?;
+
+    if byte_string_len > state.options.max_string_length as u64 {
+        return Err(failure(input, BencodeErrorKind::StringTooLong));
     }
 
+    let (remaining, _) = cut(char(':'))(remaining)?;
+
+    cut(take(byte_string_len))(remaining)
 }
 
-fn digit1_or_negative(input: &[u8]) -> IResult<&[u8], &[u8]> {
-    input.split_at_position1_complete(|item| !(item.is_dec_digit() || item == b'-'), ErrorKind::Digit)
+fn parse_value<'a>(input: &'a [u8], state: &ParseState, depth: usize) -> ParseResult<'a, BencodeRef<'a>> {
+    let (remaining, value) = alt((
+        map(|i| parse_integer(i, state), BencodeRef::Integer),
+        map(|i| parse_list(i, state, depth), BencodeRef::List),
+        map(|i| parse_dictionary(i, state, depth, |i| parse_value(i, state, depth + 1)), BencodeRef::Dictionary),
+        map(|i| parse_bytes(i, state), BencodeRef::Bytes),
+    ))(input)?;
+
+    // Only successfully parsed values are counted, as many0 tries (and fails) to parse a value at the end of every list
+    let elements = state.elements.get() + 1;
+    if elements > state.options.max_elements {
+        return Err(failure(input, BencodeErrorKind::ElementLimitExceeded));
+    }
+    state.elements.set(elements);
+
+    Ok((remaining, value))
 }
 
-fn parse_integer(input: &[u8]) -> IResult<&[u8], BencodeValue> {
-    map(
-        delimited(
-            char('i'),
-            map_res(map_res(digit1_or_negative, std::str::from_utf8), str::parse),
-            char('e')
-        ),
-        BencodeValue::Integer
-    )(input)
-}
-
-fn parse_list(input: &[u8]) -> IResult<&[u8], BencodeValue> {
-    map(
-        delimited(
-                char('l'), 
-                many0(parse_bencode), 
-                char('e')
-        ),
-        BencodeValue::List
-    )(input)
-}
-
-fn parse_dictionary_pair(input: &[u8]) -> IResult<&[u8], (BencodeBytes, BencodeValue)> {
-    tuple((
-        parse_bytes,
-        parse_bencode
-    ))(input)
-}
-
-fn parse_dictionary(input: &[u8]) -> IResult<&[u8], BencodeValue> {
-    map(
-map(
-    delimited(
-                char('d'), 
-                many0(parse_dictionary_pair), 
-                char('e')
-            ),
-            |tuple_vec| {
-                tuple_vec.into_iter().collect()
-            }
-        ),
-        BencodeValue::Dictionary
-    )(input)
+// Parses a value without copying any of its byte strings; convert the result into a BencodeValue for an owned copy
+pub fn parse_bencode<'a>(input: &'a [u8], options: &ParseOptions) -> ParseResult<'a, BencodeRef<'a>> {
+    parse_value(input, &ParseState::new(options), 0)
+}
+
+// Parses the whole of input as a single value, reporting any error along with its byte offset
+pub fn decode<'a>(input: &'a [u8], options: &ParseOptions) -> Result<BencodeRef<'a>, BencodeError> {
+    match parse_bencode(input, options) {
+        Ok(([], value)) => Ok(value),
+        Ok((remaining, _)) => Err(BencodeError::new(
+            BencodeErrorKind::TrailingData,
+            Some(input.len() - remaining.len()),
+        )),
+        Err(nom::Err::Error(e) | nom::Err::Failure(e)) => Err(e.into_bencode_error(input)),
+        Err(nom::Err::Incomplete(_)) => Err(BencodeError::new(BencodeErrorKind::UnexpectedEnd, Some(input.len()))),
+    }
+}
 
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    out.extend(bytes.len().to_string().as_bytes());
+    out.push(b':');
+    out.extend(bytes);
 }
 
-fn parse_bytes(input: &[u8]) -> IResult<&[u8], BencodeBytes> {
-    let (rest, byte_string_len): (&[u8], u64) = map_res(map_res(digit1, std::str::from_utf8), str::parse)(input)?;
-    let (rest, _) = char(':')(rest)?;
-    let (rest, bytes) = take(byte_string_len)(rest)?;
+fn encode_into(value: &BencodeValue, out: &mut Vec<u8>) {
+    match value {
+        BencodeValue::Bytes(bytes) => encode_bytes(bytes, out),
+        BencodeValue::Integer(int) => {
+            // i64's Display impl never emits leading zeros or "-0", so this is already canonical
+            out.push(b'i');
+            out.extend(int.to_string().as_bytes());
+            out.push(b'e');
+        }
+        BencodeValue::List(list) => {
+            out.push(b'l');
+            for item in list {
+                encode_into(item, out);
+            }
+            out.push(b'e');
+        }
+        BencodeValue::Dictionary(dict) => {
+            // Canonical bencode requires dictionary keys to be sorted as raw byte strings
+            let mut keys: Vec<&BencodeBytes> = dict.keys().collect();
+            keys.sort();
+
+            out.push(b'd');
+            for key in keys {
+                encode_bytes(key, out);
+                encode_into(&dict[key], out);
+            }
+            out.push(b'e');
+        }
+    }
+}
 
-    Ok((rest, bytes.to_vec()))
+// Encodes a value in its canonical form, such that parsing the output with parse_bencode yields the same value
+pub fn encode_bencode(value: &BencodeValue) -> Vec<u8> {
+    let mut out = Vec::new();
+    encode_into(value, &mut out);
+    out
 }
 
-pub fn parse_bencode(input: &[u8]) -> IResult<&[u8], BencodeValue> {
-    alt((
-        parse_integer,
-        parse_list,
-        parse_dictionary,
-        map(parse_bytes, BencodeValue::Bytes),
-    ))(input)
+// The info_hash of a metainfo file is defined as the sha1 hash of the raw value of the "info" key of the file.
+// Rather than re-encoding the decoded value (which would change the hash of any non-canonical input), this
+// parses a dictionary while leaving each of its values as the raw bytes they were parsed from.
+// Every value is still validated with parse_bencode, and is a sub-slice of the input, so its byte range
+// within the input can be recovered from the slice if needed.
+pub fn parse_dictionary_raw<'a>(input: &'a [u8], options: &ParseOptions) -> ParseResult<'a, Vec<(&'a [u8], &'a [u8])>> {
+    let state = ParseState::new(options);
+    parse_dictionary(input, &state, 0, recognize(|i| parse_value(i, &state, 1)))
 }
 
-// The info_hash of a metainfo file is defined as the sha1 hash of the raw value of the "info" key of the file
-// Given nom doesn't give us a way to return the byte range (without using something like nom_locate), and the alternative
-// is writing a bencode serializer (which no other part of the protocol requires), this parse function allows us to return the
-// raw byte representation of the info dictionary
-// TODO: this assumes the first time the bytestring "info" appears is as the key of the info dict. Replace with something a bit more robust.
-pub fn parse_info_dict_raw(input: &[u8]) -> IResult<&[u8], &[u8]> {
-    let (remaining, _) = take_until("4:info".as_bytes())(input)?;
-    let (remaining, _) = take(6usize)(remaining)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &[u8]) -> BencodeValue {
+        let (remaining, value) = parse_bencode(input, &ParseOptions::strict()).unwrap();
+        assert!(remaining.is_empty());
+        value.into()
+    }
+
+    // Encoding, parsing the output and encoding again must give the same bytes back
+    fn assert_round_trips(value: &BencodeValue) -> Vec<u8> {
+        let encoded = encode_bencode(value);
+        assert_eq!(encode_bencode(&parse(&encoded)), encoded);
+        encoded
+    }
+
+    #[test]
+    fn round_trips_every_type() {
+        let value = BencodeValue::Dictionary(HashMap::from([
+            (b"bytes".to_vec(), BencodeValue::Bytes(b"spam".to_vec())),
+            (b"empty".to_vec(), BencodeValue::Bytes(Vec::new())),
+            (b"binary".to_vec(), BencodeValue::Bytes(vec![0, 255, b':', b'e'])),
+            (b"int".to_vec(), BencodeValue::Integer(42)),
+            (
+                b"list".to_vec(),
+                BencodeValue::List(vec![
+                    BencodeValue::Integer(1),
+                    BencodeValue::List(Vec::new()),
+                    BencodeValue::Dictionary(HashMap::new()),
+                ]),
+            ),
+        ]));
+
+        assert_eq!(
+            assert_round_trips(&value),
+            b"d6:binary4:\x00\xff:e5:bytes4:spam5:empty0:3:inti42e4:listli1eledeee".to_vec()
+        );
+    }
+
+    #[test]
+    fn sorts_keys_as_raw_bytes() {
+        let value = BencodeValue::Dictionary(HashMap::from([
+            (b"b".to_vec(), BencodeValue::Integer(1)),
+            (b"a".to_vec(), BencodeValue::Integer(2)),
+            (b"B".to_vec(), BencodeValue::Integer(3)),
+            (b"ab".to_vec(), BencodeValue::Integer(4)),
+            (vec![0xff], BencodeValue::Integer(5)),
+        ]));
+
+        assert_eq!(assert_round_trips(&value), b"d1:Bi3e1:ai2e2:abi4e1:bi1e1:\xffi5ee".to_vec());
+    }
+
+    #[test]
+    fn encodes_zero_and_negative_integers_canonically() {
+        for (int, expected) in [
+            (0, &b"i0e"[..]),
+            (-1, b"i-1e"),
+            (-42, b"i-42e"),
+            (i64::MIN, b"i-9223372036854775808e"),
+            (i64::MAX, b"i9223372036854775807e"),
+        ] {
+            assert_eq!(assert_round_trips(&BencodeValue::Integer(int)), expected);
+        }
+    }
+
+    #[test]
+    fn canonicalizes_unsorted_input() {
+        let value = parse_bencode(b"d1:bi1e1:ai2ee", &ParseOptions::default()).unwrap().1;
+        assert_eq!(encode_bencode(&value.into()), b"d1:ai2e1:bi1ee".to_vec());
+    }
+
+    fn decode_error(input: &[u8], options: &ParseOptions) -> (BencodeErrorKind, Option<usize>) {
+        let error = decode(input, options).unwrap_err();
+        (error.kind, error.offset)
+    }
+
+    #[test]
+    fn strict_mode_rejects_non_canonical_input() {
+        let strict = ParseOptions::strict();
 
-    let (remaining_after_info_dict, _) = parse_dictionary(remaining)?;
-    let info_dict_length = remaining.len() - remaining_after_info_dict.len();
+        assert_eq!(decode_error(b"i-0e", &strict), (BencodeErrorKind::NegativeZero, Some(1)));
+        assert_eq!(decode_error(b"i03e", &strict), (BencodeErrorKind::LeadingZero, Some(1)));
+        assert_eq!(decode_error(b"i-03e", &strict), (BencodeErrorKind::LeadingZero, Some(1)));
+        assert_eq!(decode_error(b"03:abc", &strict), (BencodeErrorKind::LeadingZero, Some(0)));
+        assert_eq!(decode_error(b"li1e03:abce", &strict), (BencodeErrorKind::LeadingZero, Some(4)));
+        // Key errors point at the offending key
+        assert_eq!(decode_error(b"d1:bi1e1:ai2ee", &strict), (BencodeErrorKind::UnsortedKey, Some(7)));
+        assert_eq!(decode_error(b"d1:ai1e1:ai2ee", &strict), (BencodeErrorKind::DuplicateKey, Some(7)));
+    }
+
+    #[test]
+    fn lenient_mode_accepts_non_canonical_input() {
+        let lenient = ParseOptions::default();
+
+        assert_eq!(decode(b"i-0e", &lenient), Ok(BencodeRef::Integer(0)));
+        assert_eq!(decode(b"i03e", &lenient), Ok(BencodeRef::Integer(3)));
+        assert_eq!(decode(b"i-03e", &lenient), Ok(BencodeRef::Integer(-3)));
+        assert_eq!(decode(b"03:abc", &lenient), Ok(BencodeRef::Bytes(b"abc")));
+        // Dictionary entries are kept in the order they appeared, duplicates and all
+        assert_eq!(
+            decode(b"d1:bi1e1:ai2ee", &lenient),
+            Ok(BencodeRef::Dictionary(vec![(&b"b"[..], BencodeRef::Integer(1)), (&b"a"[..], BencodeRef::Integer(2))]))
+        );
+        assert_eq!(
+            decode(b"d1:ai1e1:ai2ee", &lenient),
+            Ok(BencodeRef::Dictionary(vec![(&b"a"[..], BencodeRef::Integer(1)), (&b"a"[..], BencodeRef::Integer(2))]))
+        );
+    }
+
+    #[test]
+    fn deep_nesting_hits_the_depth_limit() {
+        // Deep enough to overflow the stack, were it not for the limit
+        let input = vec![b'l'; 100_000];
+        assert_eq!(decode_error(&input, &ParseOptions::default()), (BencodeErrorKind::DepthLimitExceeded, Some(64)));
+
+        let mut input = vec![b'l'; 64];
+        input.extend(vec![b'e'; 64]);
+        assert!(decode(&input, &ParseOptions::default()).is_ok());
+    }
 
-    Ok((remaining_after_info_dict, &remaining[0..info_dict_length]))
-}
\ No newline at end of file
+    #[test]
+    fn too_many_values_hit_the_element_limit() {
+        let options = ParseOptions { max_elements: 3, ..ParseOptions::default() };
+
+        // The list itself is the fourth value
+        assert_eq!(decode_error(b"li1ei2ei3ee", &options), (BencodeErrorKind::ElementLimitExceeded, Some(0)));
+        assert!(decode(b"li1ei2ee", &options).is_ok());
+    }
+
+    #[test]
+    fn oversized_length_prefix_hits_the_string_limit() {
+        // Rejected from the length alone, long before the end of the input is reached
+        assert_eq!(decode_error(b"l268435457:spame", &ParseOptions::default()), (BencodeErrorKind::StringTooLong, Some(1)));
+
+        let options = ParseOptions { max_string_length: 4, ..ParseOptions::default() };
+        assert_eq!(decode_error(b"5:spams", &options), (BencodeErrorKind::StringTooLong, Some(0)));
+        assert!(decode(b"4:spam", &options).is_ok());
+    }
+}
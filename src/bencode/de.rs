@@ -0,0 +1,391 @@
+use std::collections::HashMap;
+
+use serde::{
+    de::{
+        self, value::BorrowedBytesDeserializer, DeserializeSeed, EnumAccess, MapAccess, SeqAccess,
+        VariantAccess, Visitor,
+    },
+    forward_to_deserialize_any, Deserialize, Deserializer,
+};
+
+use super::{decode, BencodeError, BencodeRef, ParseOptions};
+
+// Deserializes a T from a complete bencoded value. Any bytes left over after that value are an error.
+// Byte strings and strs in T may borrow directly from bytes.
+pub fn from_bytes<'de, T: Deserialize<'de>>(bytes: &'de [u8]) -> Result<T, BencodeError> {
+    from_ref(&decode(bytes, &ParseOptions::default())?)
+}
+
+// Deserializes a T from an already-parsed value, borrowing byte strings from the original input where T allows
+pub fn from_ref<'de, T: Deserialize<'de>>(value: &BencodeRef<'de>) -> Result<T, BencodeError> {
+    T::deserialize(value)
+}
+
+impl<'de> Deserializer<'de> for &BencodeRef<'de> {
+    type Error = BencodeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, BencodeError> {
+        match self {
+            BencodeRef::Bytes(bytes) => visitor.visit_borrowed_bytes(bytes),
+            BencodeRef::Integer(int) => visitor.visit_i64(*int),
+            BencodeRef::List(list) => visitor.visit_seq(ListAccess {
+                iter: list.iter(),
+                index: 0,
+            }),
+            BencodeRef::Dictionary(dict) => visitor.visit_map(DictionaryAccess {
+                iter: last_occurrences(dict).into_iter(),
+                next: None,
+            }),
+        }
+    }
+
+    // Bencode has no boolean type; the convention is to use the integers 0 and 1
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, BencodeError> {
+        match self {
+            BencodeRef::Integer(0) => visitor.visit_bool(false),
+            BencodeRef::Integer(1) => visitor.visit_bool(true),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, BencodeError> {
+        match self {
+            BencodeRef::Bytes(bytes) => match std::str::from_utf8(bytes) {
+                Ok(str) => visitor.visit_borrowed_str(str),
+                Err(_) => Err(de::Error::invalid_value(
+                    de::Unexpected::Bytes(bytes),
+                    &"a UTF-8 byte string",
+                )),
+            },
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, BencodeError> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, BencodeError> {
+        self.deserialize_str(visitor)
+    }
+
+    // Fields that are missing entirely are handled by serde itself; any value that is present is a Some
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, BencodeError> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, BencodeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    // Unit variants are encoded as their name, and all other variants as a single-entry
+    // dictionary mapping the variant's name to its contents
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, BencodeError> {
+        match self {
+            BencodeRef::Bytes(variant) => visitor.visit_enum(Enum {
+                variant,
+                value: None,
+            }),
+            BencodeRef::Dictionary(dict) if dict.len() == 1 => {
+                let (variant, value) = &dict[0];
+                visitor.visit_enum(Enum {
+                    variant,
+                    value: Some(value),
+                })
+            }
+            _ => Err(de::Error::invalid_type(unexpected(self), &"an enum variant")),
+        }
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, BencodeError> {
+        visitor.visit_unit()
+    }
+
+    forward_to_deserialize_any! {
+        i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 bytes byte_buf
+        unit unit_struct seq tuple tuple_struct map struct identifier
+    }
+}
+
+fn unexpected<'a>(value: &'a BencodeRef) -> de::Unexpected<'a> {
+    match value {
+        BencodeRef::Bytes(bytes) => de::Unexpected::Bytes(bytes),
+        BencodeRef::Integer(int) => de::Unexpected::Signed(*int),
+        BencodeRef::List(_) => de::Unexpected::Seq,
+        BencodeRef::Dictionary(_) => de::Unexpected::Map,
+    }
+}
+
+struct ListAccess<'a, 'de> {
+    iter: std::slice::Iter<'a, BencodeRef<'de>>,
+    index: usize,
+}
+
+impl<'a, 'de> SeqAccess<'de> for ListAccess<'a, 'de> {
+    type Error = BencodeError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, BencodeError> {
+        let index = self.index;
+        self.index += 1;
+
+        self.iter
+            .next()
+            .map(|item| {
+                seed.deserialize(item)
+                    .map_err(|e| e.within(format!("[{}]", index)))
+            })
+            .transpose()
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+// Leniently parsed dictionaries may repeat a key, in which case only its last occurrence is kept,
+// as serde would otherwise reject the input for having a duplicate field
+fn last_occurrences<'a, 'de>(dict: &'a [(&'de [u8], BencodeRef<'de>)]) -> Vec<&'a (&'de [u8], BencodeRef<'de>)> {
+    let last_index: HashMap<&[u8], usize> = dict.iter().enumerate().map(|(i, (key, _))| (*key, i)).collect();
+
+    dict.iter()
+        .enumerate()
+        .filter(|(i, (key, _))| last_index[key] == *i)
+        .map(|(_, entry)| entry)
+        .collect()
+}
+
+struct DictionaryAccess<'a, 'de> {
+    iter: std::vec::IntoIter<&'a (&'de [u8], BencodeRef<'de>)>,
+    // The entry whose key was most recently handed out, and whose value is to be deserialized next
+    next: Option<&'a (&'de [u8], BencodeRef<'de>)>,
+}
+
+impl<'a, 'de> MapAccess<'de> for DictionaryAccess<'a, 'de> {
+    type Error = BencodeError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, BencodeError> {
+        match self.iter.next() {
+            Some(entry) => {
+                self.next = Some(entry);
+                seed.deserialize(BorrowedBytesDeserializer::new(entry.0)).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, BencodeError> {
+        let (key, value) = self
+            .next
+            .take()
+            .ok_or_else(|| BencodeError::message("Dictionary value requested before its key"))?;
+
+        seed.deserialize(value)
+            .map_err(|e| e.within(String::from_utf8_lossy(key).into_owned()))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+struct Enum<'a, 'de> {
+    variant: &'de [u8],
+    value: Option<&'a BencodeRef<'de>>,
+}
+
+impl<'a, 'de> EnumAccess<'de> for Enum<'a, 'de> {
+    type Error = BencodeError;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), BencodeError> {
+        let variant = seed.deserialize(BorrowedBytesDeserializer::new(self.variant))?;
+        Ok((variant, self))
+    }
+}
+
+impl<'a, 'de> VariantAccess<'de> for Enum<'a, 'de> {
+    type Error = BencodeError;
+
+    fn unit_variant(self) -> Result<(), BencodeError> {
+        match self.value {
+            None => Ok(()),
+            Some(value) => Err(de::Error::invalid_type(unexpected(value), &"a unit variant")),
+        }
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, BencodeError> {
+        match self.value {
+            Some(value) => seed.deserialize(value),
+            None => Err(de::Error::invalid_type(de::Unexpected::UnitVariant, &"a newtype variant")),
+        }
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, BencodeError> {
+        match self.value {
+            Some(value) => value.deserialize_seq(visitor),
+            None => Err(de::Error::invalid_type(de::Unexpected::UnitVariant, &"a tuple variant")),
+        }
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, BencodeError> {
+        match self.value {
+            Some(value) => value.deserialize_map(visitor),
+            None => Err(de::Error::invalid_type(de::Unexpected::UnitVariant, &"a struct variant")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Serialize;
+    use serde_bytes::ByteBuf;
+
+    use super::*;
+    use crate::bencode::to_bytes;
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct Response<'a> {
+        interval: u64,
+        #[serde(rename = "min interval")]
+        min_interval: Option<u64>,
+        #[serde(rename = "warning message")]
+        warning_message: Option<&'a str>,
+        tracker_id: Option<ByteBuf>,
+    }
+
+    #[test]
+    fn missing_optional_fields_are_none() {
+        let response: Response = from_bytes(b"d8:intervali1800e15:warning message7:be nicee").unwrap();
+
+        assert_eq!(
+            response,
+            Response {
+                interval: 1800,
+                min_interval: None,
+                warning_message: Some("be nice"),
+                tracker_id: None,
+            }
+        );
+    }
+
+    #[test]
+    fn missing_required_field_is_an_error() {
+        let error = from_bytes::<Response>(b"d12:min intervali60ee").unwrap_err();
+        assert_eq!(error.to_string(), "missing field `interval`");
+    }
+
+    #[test]
+    fn unknown_fields_are_ignored() {
+        let response: Response = from_bytes(b"d8:completei5e8:intervali60e5:peersld2:ip9:127.0.0.1eee").unwrap();
+        assert_eq!(response.interval, 60);
+    }
+
+    #[test]
+    fn flattened_struct_round_trips() {
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Stats {
+            complete: u32,
+            incomplete: u32,
+        }
+
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Tracker {
+            url: String,
+            #[serde(flatten)]
+            stats: Stats,
+            id: Option<ByteBuf>,
+        }
+
+        let tracker = Tracker {
+            url: "http://tracker.example/announce".to_string(),
+            stats: Stats {
+                complete: 3,
+                incomplete: 0,
+            },
+            id: Some(ByteBuf::from(vec![0, 0xff])),
+        };
+
+        let bytes = to_bytes(&tracker).unwrap();
+        assert_eq!(
+            bytes,
+            b"d8:completei3e2:id2:\x00\xff10:incompletei0e3:url31:http://tracker.example/announcee".to_vec()
+        );
+        assert_eq!(from_bytes::<Tracker>(&bytes).unwrap(), tracker);
+    }
+
+    #[test]
+    fn untagged_enum_picks_matching_variant() {
+        // As trackers send peers: either compact, or as a list of dictionaries
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Peer {
+            ip: String,
+            port: u16,
+        }
+
+        #[derive(Deserialize, Debug, PartialEq)]
+        #[serde(untagged)]
+        enum Peers {
+            Compact(ByteBuf),
+            Dictionaries(Vec<Peer>),
+        }
+
+        assert_eq!(
+            from_bytes::<Peers>(b"6:\x7f\x00\x00\x01\x1a\xe1").unwrap(),
+            Peers::Compact(ByteBuf::from(vec![127, 0, 0, 1, 0x1a, 0xe1]))
+        );
+        assert_eq!(
+            from_bytes::<Peers>(b"ld2:ip9:127.0.0.14:porti6881eee").unwrap(),
+            Peers::Dictionaries(vec![Peer {
+                ip: "127.0.0.1".to_string(),
+                port: 6881,
+            }])
+        );
+        assert!(from_bytes::<Peers>(b"i6881e").is_err());
+    }
+
+    #[test]
+    fn errors_give_the_path_to_the_bad_value() {
+        type Torrent = HashMap<String, HashMap<String, Vec<HashMap<String, u64>>>>;
+
+        let error = from_bytes::<Torrent>(b"d4:infod5:filesld6:lengthi1eed6:length3:teneeee").unwrap_err();
+
+        assert_eq!(error.path, vec!["info", "files", "[1]", "length"]);
+        assert!(error.to_string().starts_with("info.files[1].length: invalid type: byte array"));
+    }
+
+    #[test]
+    fn parse_errors_give_their_offset() {
+        let error = from_bytes::<Response>(b"d8:intervali60e").unwrap_err();
+        assert_eq!(error.offset, Some(15));
+        assert!(error.path.is_empty());
+
+        let error = from_bytes::<u64>(b"i60ei0e").unwrap_err();
+        assert_eq!(error.to_string(), "trailing data after bencoded value at byte 4");
+    }
+
+    #[test]
+    fn integers_deserialize_as_bools() {
+        assert!(from_bytes::<bool>(b"i1e").unwrap());
+        assert!(!from_bytes::<bool>(b"i0e").unwrap());
+        assert!(from_bytes::<bool>(b"i2e").is_err());
+    }
+}
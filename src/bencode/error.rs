@@ -0,0 +1,140 @@
+use std::fmt;
+
+use nom::error::ErrorKind;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BencodeErrorKind {
+    UnexpectedEnd,
+    UnexpectedByte(u8),
+    InvalidInteger,
+    NegativeZero,
+    LeadingZero,
+    UnsortedKey,
+    DuplicateKey,
+    TrailingData,
+    DepthLimitExceeded,
+    ElementLimitExceeded,
+    StringTooLong,
+    // Raised by serde, e.g. for a missing field or a value of the wrong type
+    Message(String),
+}
+
+impl fmt::Display for BencodeErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BencodeErrorKind::UnexpectedEnd => write!(f, "unexpected end of input"),
+            BencodeErrorKind::UnexpectedByte(byte) => {
+                write!(f, "unexpected byte {:?}", char::from(*byte))
+            }
+            BencodeErrorKind::InvalidInteger => write!(f, "invalid integer"),
+            BencodeErrorKind::NegativeZero => write!(f, "negative zero"),
+            BencodeErrorKind::LeadingZero => write!(f, "leading zero in number"),
+            BencodeErrorKind::UnsortedKey => write!(f, "dictionary key out of order"),
+            BencodeErrorKind::DuplicateKey => write!(f, "duplicate dictionary key"),
+            BencodeErrorKind::TrailingData => write!(f, "trailing data after bencoded value"),
+            BencodeErrorKind::DepthLimitExceeded => write!(f, "lists and dictionaries nested too deeply"),
+            BencodeErrorKind::ElementLimitExceeded => write!(f, "too many values"),
+            BencodeErrorKind::StringTooLong => write!(f, "byte string too long"),
+            BencodeErrorKind::Message(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BencodeError {
+    pub kind: BencodeErrorKind,
+    // The byte offset into the input at which the error was found, where known
+    pub offset: Option<usize>,
+    // The dictionary keys and list indices leading to the value that failed to deserialize, outermost first
+    pub path: Vec<String>,
+}
+
+impl BencodeError {
+    pub fn new(kind: BencodeErrorKind, offset: Option<usize>) -> Self {
+        Self {
+            kind,
+            offset,
+            path: Vec::new(),
+        }
+    }
+
+    pub fn message<T: fmt::Display>(msg: T) -> Self {
+        Self::new(BencodeErrorKind::Message(msg.to_string()), None)
+    }
+
+    // Records that this error occurred inside the given dictionary key or list index
+    pub(super) fn within(mut self, segment: String) -> Self {
+        self.path.insert(0, segment);
+        self
+    }
+}
+
+impl fmt::Display for BencodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.path.is_empty() {
+            for (i, segment) in self.path.iter().enumerate() {
+                if i > 0 && !segment.starts_with('[') {
+                    write!(f, ".")?;
+                }
+                write!(f, "{}", segment)?;
+            }
+            write!(f, ": ")?;
+        }
+
+        write!(f, "{}", self.kind)?;
+
+        if let Some(offset) = self.offset {
+            write!(f, " at byte {}", offset)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for BencodeError {}
+
+impl serde::ser::Error for BencodeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self::message(msg)
+    }
+}
+
+impl serde::de::Error for BencodeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self::message(msg)
+    }
+}
+
+// The error type threaded through the nom parsers. It keeps hold of the remaining input at the point
+// of failure, which is turned into a byte offset once the parse is finished.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError<'a> {
+    pub input: &'a [u8],
+    pub kind: BencodeErrorKind,
+}
+
+impl<'a> ParseError<'a> {
+    pub fn new(input: &'a [u8], kind: BencodeErrorKind) -> Self {
+        Self { input, kind }
+    }
+
+    // Converts this into an error carrying its byte offset into the original input
+    pub fn into_bencode_error(self, original_input: &[u8]) -> BencodeError {
+        BencodeError::new(self.kind, Some(original_input.len() - self.input.len()))
+    }
+}
+
+impl<'a> nom::error::ParseError<&'a [u8]> for ParseError<'a> {
+    fn from_error_kind(input: &'a [u8], kind: ErrorKind) -> Self {
+        let kind = match input.first() {
+            Some(byte) if kind != ErrorKind::Eof => BencodeErrorKind::UnexpectedByte(*byte),
+            _ => BencodeErrorKind::UnexpectedEnd,
+        };
+
+        Self { input, kind }
+    }
+
+    fn append(_input: &'a [u8], _kind: ErrorKind, other: Self) -> Self {
+        other
+    }
+}
@@ -0,0 +1,450 @@
+use std::collections::HashMap;
+
+use serde::{ser, Serialize};
+
+use super::{encode_bencode, BencodeBytes, BencodeError, BencodeValue};
+
+// Serializes a value into its canonical bencoded form
+pub fn to_bytes<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, BencodeError> {
+    Ok(encode_bencode(&to_value(value)?))
+}
+
+pub fn to_value<T: Serialize + ?Sized>(value: &T) -> Result<BencodeValue, BencodeError> {
+    value
+        .serialize(Serializer)
+        .and_then(|value| value.ok_or_else(|| BencodeError::message("Cannot serialize an empty value")))
+}
+
+// Bencode has no way of representing a missing value, so None and () serialize to nothing at all.
+// Dictionaries skip such entries entirely, which is how optional fields are left out of the output.
+struct Serializer;
+
+fn unsupported(type_name: &str) -> BencodeError {
+    BencodeError::message(format!("Bencode cannot represent a {}", type_name))
+}
+
+fn variant_dictionary(variant: &'static str, value: BencodeValue) -> BencodeValue {
+    BencodeValue::Dictionary(HashMap::from([(variant.as_bytes().to_vec(), value)]))
+}
+
+impl ser::Serializer for Serializer {
+    type Ok = Option<BencodeValue>;
+    type Error = BencodeError;
+
+    type SerializeSeq = ListSerializer;
+    type SerializeTuple = ListSerializer;
+    type SerializeTupleStruct = ListSerializer;
+    type SerializeTupleVariant = ListSerializer;
+    type SerializeMap = DictionarySerializer;
+    type SerializeStruct = DictionarySerializer;
+    type SerializeStructVariant = DictionarySerializer;
+
+    fn serialize_bool(self, v: bool) -> Result<Self::Ok, BencodeError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Self::Ok, BencodeError> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Self::Ok, BencodeError> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Self::Ok, BencodeError> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Self::Ok, BencodeError> {
+        Ok(Some(BencodeValue::Integer(v)))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Self::Ok, BencodeError> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Self::Ok, BencodeError> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Self::Ok, BencodeError> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Self::Ok, BencodeError> {
+        let v = v
+            .try_into()
+            .map_err(|_| BencodeError::message(format!("Integer {} is out of range", v)))?;
+
+        self.serialize_i64(v)
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<Self::Ok, BencodeError> {
+        Err(unsupported("float"))
+    }
+
+    fn serialize_f64(self, _v: f64) -> Result<Self::Ok, BencodeError> {
+        Err(unsupported("float"))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Self::Ok, BencodeError> {
+        self.serialize_str(v.encode_utf8(&mut [0u8; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Self::Ok, BencodeError> {
+        self.serialize_bytes(v.as_bytes())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Self::Ok, BencodeError> {
+        Ok(Some(BencodeValue::Bytes(v.to_vec())))
+    }
+
+    fn serialize_none(self) -> Result<Self::Ok, BencodeError> {
+        Ok(None)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Self::Ok, BencodeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Self::Ok, BencodeError> {
+        Ok(None)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Self::Ok, BencodeError> {
+        Ok(None)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Self::Ok, BencodeError> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, BencodeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, BencodeError> {
+        Ok(Some(variant_dictionary(variant, to_value(value)?)))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, BencodeError> {
+        Ok(ListSerializer {
+            variant: None,
+            items: Vec::with_capacity(len.unwrap_or(0)),
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, BencodeError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleStruct, BencodeError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleVariant, BencodeError> {
+        Ok(ListSerializer {
+            variant: Some(variant),
+            items: Vec::with_capacity(len),
+        })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, BencodeError> {
+        Ok(DictionarySerializer {
+            variant: None,
+            entries: HashMap::new(),
+            next_key: None,
+        })
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStruct, BencodeError> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, BencodeError> {
+        Ok(DictionarySerializer {
+            variant: Some(variant),
+            entries: HashMap::new(),
+            next_key: None,
+        })
+    }
+}
+
+struct ListSerializer {
+    variant: Option<&'static str>,
+    items: Vec<BencodeValue>,
+}
+
+impl ListSerializer {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), BencodeError> {
+        // Skipping a None here would silently shift the position of every item after it
+        let item = value
+            .serialize(Serializer)?
+            .ok_or_else(|| BencodeError::message("Cannot serialize an empty value inside a list"))?;
+
+        self.items.push(item);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Option<BencodeValue>, BencodeError> {
+        let list = BencodeValue::List(self.items);
+
+        Ok(Some(match self.variant {
+            Some(variant) => variant_dictionary(variant, list),
+            None => list,
+        }))
+    }
+}
+
+impl ser::SerializeSeq for ListSerializer {
+    type Ok = Option<BencodeValue>;
+    type Error = BencodeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), BencodeError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, BencodeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for ListSerializer {
+    type Ok = Option<BencodeValue>;
+    type Error = BencodeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), BencodeError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, BencodeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for ListSerializer {
+    type Ok = Option<BencodeValue>;
+    type Error = BencodeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), BencodeError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, BencodeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for ListSerializer {
+    type Ok = Option<BencodeValue>;
+    type Error = BencodeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), BencodeError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, BencodeError> {
+        self.finish()
+    }
+}
+
+struct DictionarySerializer {
+    variant: Option<&'static str>,
+    entries: HashMap<BencodeBytes, BencodeValue>,
+    next_key: Option<BencodeBytes>,
+}
+
+impl DictionarySerializer {
+    fn insert<T: Serialize + ?Sized>(&mut self, key: BencodeBytes, value: &T) -> Result<(), BencodeError> {
+        if let Some(value) = value.serialize(Serializer)? {
+            self.entries.insert(key, value);
+        }
+
+        Ok(())
+    }
+
+    // Entries are sorted by encode_bencode, so the order they were serialized in doesn't matter
+    fn finish(self) -> Result<Option<BencodeValue>, BencodeError> {
+        let dict = BencodeValue::Dictionary(self.entries);
+
+        Ok(Some(match self.variant {
+            Some(variant) => variant_dictionary(variant, dict),
+            None => dict,
+        }))
+    }
+}
+
+impl ser::SerializeMap for DictionarySerializer {
+    type Ok = Option<BencodeValue>;
+    type Error = BencodeError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), BencodeError> {
+        match key.serialize(Serializer)? {
+            Some(BencodeValue::Bytes(key)) => {
+                self.next_key = Some(key);
+                Ok(())
+            }
+            _ => Err(BencodeError::message("Dictionary keys must be byte strings")),
+        }
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), BencodeError> {
+        let key = self
+            .next_key
+            .take()
+            .ok_or_else(|| BencodeError::message("Dictionary value serialized before its key"))?;
+
+        self.insert(key, value)
+    }
+
+    fn end(self) -> Result<Self::Ok, BencodeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for DictionarySerializer {
+    type Ok = Option<BencodeValue>;
+    type Error = BencodeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), BencodeError> {
+        self.insert(key.as_bytes().to_vec(), value)
+    }
+
+    fn end(self) -> Result<Self::Ok, BencodeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for DictionarySerializer {
+    type Ok = Option<BencodeValue>;
+    type Error = BencodeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), BencodeError> {
+        self.insert(key.as_bytes().to_vec(), value)
+    }
+
+    fn end(self) -> Result<Self::Ok, BencodeError> {
+        self.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Serialize;
+    use serde_bytes::ByteBuf;
+
+    use super::*;
+
+    #[derive(Serialize)]
+    struct Announce {
+        info_hash: ByteBuf,
+        port: u16,
+        compact: bool,
+        event: Option<String>,
+        #[serde(rename = "trackerid")]
+        tracker_id: Option<ByteBuf>,
+    }
+
+    #[test]
+    fn skips_none_fields() {
+        let announce = Announce {
+            info_hash: ByteBuf::from(vec![0xff, 0]),
+            port: 6881,
+            compact: true,
+            event: None,
+            tracker_id: Some(ByteBuf::from(b"xyz".to_vec())),
+        };
+
+        assert_eq!(
+            to_bytes(&announce).unwrap(),
+            b"d7:compacti1e9:info_hash2:\xff\x004:porti6881e9:trackerid3:xyze".to_vec()
+        );
+    }
+
+    #[test]
+    fn merges_flattened_fields_in_sorted_order() {
+        #[derive(Serialize)]
+        struct Inner {
+            b: u32,
+            d: Option<u32>,
+        }
+
+        #[derive(Serialize)]
+        struct Outer {
+            c: &'static str,
+            #[serde(flatten)]
+            inner: Inner,
+            a: Vec<i64>,
+        }
+
+        let outer = Outer {
+            c: "spam",
+            inner: Inner { b: 1, d: None },
+            a: vec![-1, 0],
+        };
+
+        assert_eq!(to_bytes(&outer).unwrap(), b"d1:ali-1ei0ee1:bi1e1:c4:spame".to_vec());
+    }
+
+    #[test]
+    fn encodes_enums() {
+        #[derive(Serialize)]
+        enum Event {
+            Started,
+            Progress(u32),
+        }
+
+        assert_eq!(to_bytes(&Event::Started).unwrap(), b"7:Started".to_vec());
+        assert_eq!(to_bytes(&Event::Progress(50)).unwrap(), b"d8:Progressi50ee".to_vec());
+    }
+
+    #[test]
+    fn rejects_unrepresentable_values() {
+        assert!(to_bytes(&None::<u32>).is_err());
+        assert!(to_bytes(&1.5f64).is_err());
+        assert!(to_bytes(&u64::MAX).is_err());
+    }
+}
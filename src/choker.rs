@@ -0,0 +1,309 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+// How often the unchoked peers are chosen again
+pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+// How often the optimistic unchoke moves on to another peer
+const OPTIMISTIC_UNCHOKE_INTERVAL: Duration = Duration::from_secs(30);
+// A peer that's sent us no blocks for this long is snubbing us
+const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+
+struct ChokerPeer {
+    interested: bool,
+    choked: bool,
+    // Bytes transferred since the last rechoke
+    downloaded: u64,
+    uploaded: u64,
+    // When the peer last sent us a block, or when it connected if it never has
+    last_block: Instant,
+    // When the peer was last given the optimistic unchoke, if ever
+    last_optimistic_unchoke: Option<Instant>,
+}
+
+// Decides which peers we upload to, by tit-for-tat: the peers that upload the most to us are unchoked in return,
+// or while we're seeding, the peers we can upload the most to. One slot is kept for an optimistic unchoke,
+// which gives each other peer a turn, so that we find out who'd upload to us given the chance.
+// Peers that have snubbed us only ever get the optimistic unchoke.
+// Time is passed in, rather than read from the clock, so the choker's decisions depend only on what it's told.
+pub struct Choker {
+    upload_slots: usize,
+    peers: HashMap<SocketAddr, ChokerPeer>,
+    optimistic: Option<SocketAddr>,
+    optimistic_since: Option<Instant>,
+}
+
+impl Choker {
+    pub fn new(upload_slots: usize) -> Self {
+        Self {
+            upload_slots,
+            peers: HashMap::new(),
+            optimistic: None,
+            optimistic_since: None,
+        }
+    }
+
+    // Peers start off choked
+    pub fn add_peer(&mut self, peer: SocketAddr, now: Instant) {
+        self.peers.insert(
+            peer,
+            ChokerPeer {
+                interested: false,
+                choked: true,
+                downloaded: 0,
+                uploaded: 0,
+                last_block: now,
+                last_optimistic_unchoke: None,
+            },
+        );
+    }
+
+    pub fn remove_peer(&mut self, peer: &SocketAddr) {
+        self.peers.remove(peer);
+        if self.optimistic == Some(*peer) {
+            self.optimistic = None;
+        }
+    }
+
+    pub fn set_interested(&mut self, peer: &SocketAddr, interested: bool) {
+        if let Some(choker_peer) = self.peers.get_mut(peer) {
+            choker_peer.interested = interested;
+        }
+    }
+
+    pub fn record_downloaded(&mut self, peer: &SocketAddr, bytes: u64, now: Instant) {
+        if let Some(choker_peer) = self.peers.get_mut(peer) {
+            choker_peer.downloaded += bytes;
+            choker_peer.last_block = now;
+        }
+    }
+
+    pub fn record_uploaded(&mut self, peer: &SocketAddr, bytes: u64) {
+        if let Some(choker_peer) = self.peers.get_mut(peer) {
+            choker_peer.uploaded += bytes;
+        }
+    }
+
+    // Picks the peers to unchoke, to be called every RECHOKE_INTERVAL.
+    // Returns the peers whose choke state has changed, and whether each is now choked.
+    pub fn rechoke(&mut self, now: Instant, seeding: bool) -> Vec<(SocketAddr, bool)> {
+        let snubbed = |choker_peer: &ChokerPeer| !seeding && now.duration_since(choker_peer.last_block) >= SNUB_TIMEOUT;
+
+        // Ties are broken by address, so the outcome doesn't depend on the order of the hash map
+        let mut candidates: Vec<(&SocketAddr, &ChokerPeer)> = self
+            .peers
+            .iter()
+            .filter(|(_, choker_peer)| choker_peer.interested && !snubbed(choker_peer))
+            .collect();
+        candidates.sort_by_key(|(peer, choker_peer)| {
+            let rate = if seeding { choker_peer.uploaded } else { choker_peer.downloaded };
+            (std::cmp::Reverse(rate), **peer)
+        });
+
+        let mut unchoked: Vec<SocketAddr> = candidates
+            .iter()
+            .take(self.upload_slots.saturating_sub(1))
+            .map(|(peer, _)| **peer)
+            .collect();
+
+        // The optimistic unchoke stays put until its time is up, unless its peer has since earned a regular slot
+        // or lost interest
+        let optimistic_expired = self
+            .optimistic_since
+            .is_none_or(|since| now.duration_since(since) >= OPTIMISTIC_UNCHOKE_INTERVAL);
+        let optimistic_still_valid = self.optimistic.is_some_and(|peer| {
+            !unchoked.contains(&peer) && self.peers.get(&peer).is_some_and(|choker_peer| choker_peer.interested)
+        });
+
+        if optimistic_expired || !optimistic_still_valid {
+            // Whoever's waited longest for a turn goes next, with peers that have never had one first
+            self.optimistic = self
+                .peers
+                .iter()
+                .filter(|(peer, choker_peer)| choker_peer.interested && !unchoked.contains(peer))
+                .min_by_key(|(peer, choker_peer)| (choker_peer.last_optimistic_unchoke, **peer))
+                .map(|(peer, _)| *peer);
+            self.optimistic_since = Some(now);
+
+            if let Some(choker_peer) = self.optimistic.and_then(|peer| self.peers.get_mut(&peer)) {
+                choker_peer.last_optimistic_unchoke = Some(now);
+            }
+        }
+
+        if self.upload_slots > 0 {
+            unchoked.extend(self.optimistic);
+        }
+
+        let mut changes = Vec::new();
+        for (peer, choker_peer) in self.peers.iter_mut() {
+            let choked = !unchoked.contains(peer);
+            if choked != choker_peer.choked {
+                choker_peer.choked = choked;
+                changes.push((*peer, choked));
+            }
+
+            choker_peer.downloaded = 0;
+            choker_peer.uploaded = 0;
+        }
+
+        changes.sort();
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    // A choker with the given interested peers, all connected at base
+    fn choker_with_peers(upload_slots: usize, ports: &[u16], base: Instant) -> Choker {
+        let mut choker = Choker::new(upload_slots);
+        for port in ports {
+            choker.add_peer(peer(*port), base);
+            choker.set_interested(&peer(*port), true);
+        }
+        choker
+    }
+
+    fn unchoked(changes: &[(SocketAddr, bool)]) -> Vec<SocketAddr> {
+        changes.iter().filter(|(_, choked)| !choked).map(|(peer, _)| *peer).collect()
+    }
+
+    #[test]
+    fn unchokes_fastest_uploaders_to_us_while_leeching() {
+        let base = Instant::now();
+        let mut choker = choker_with_peers(3, &[1, 2, 3, 4, 5], base);
+        choker.record_downloaded(&peer(4), 300, base);
+        choker.record_downloaded(&peer(2), 200, base);
+        choker.record_downloaded(&peer(5), 100, base);
+        // Uploads count for nothing while we're still downloading
+        choker.record_uploaded(&peer(3), 1000);
+
+        // Peers 4 and 2 get the regular slots, and peer 1 (the lowest address never to have had a turn) the optimistic one
+        assert_eq!(choker.rechoke(base, false), vec![(peer(1), false), (peer(2), false), (peer(4), false)]);
+    }
+
+    #[test]
+    fn unchokes_fastest_downloaders_from_us_while_seeding() {
+        let base = Instant::now();
+        let mut choker = choker_with_peers(3, &[1, 2, 3, 4, 5], base);
+        choker.record_uploaded(&peer(5), 300);
+        choker.record_uploaded(&peer(3), 200);
+        choker.record_downloaded(&peer(2), 1000, base);
+
+        assert_eq!(choker.rechoke(base, true), vec![(peer(1), false), (peer(3), false), (peer(5), false)]);
+    }
+
+    #[test]
+    fn breaks_ties_by_address() {
+        let base = Instant::now();
+        let ports = [9, 3, 7, 1, 5];
+
+        let mut first = choker_with_peers(3, &ports, base);
+        let mut reversed: Vec<u16> = ports.to_vec();
+        reversed.reverse();
+        let mut second = choker_with_peers(3, &reversed, base);
+
+        let changes = first.rechoke(base, false);
+        assert_eq!(unchoked(&changes), vec![peer(1), peer(3), peer(5)]);
+        assert_eq!(second.rechoke(base, false), changes);
+    }
+
+    #[test]
+    fn ignores_uninterested_peers() {
+        let base = Instant::now();
+        let mut choker = choker_with_peers(4, &[1, 2], base);
+        choker.add_peer(peer(3), base);
+        choker.record_downloaded(&peer(3), 1000, base);
+
+        assert_eq!(unchoked(&choker.rechoke(base, false)), vec![peer(1), peer(2)]);
+
+        // Losing interest loses the slot
+        choker.set_interested(&peer(2), false);
+        assert_eq!(choker.rechoke(base + RECHOKE_INTERVAL, false), vec![(peer(2), true)]);
+    }
+
+    #[test]
+    fn rotates_optimistic_unchoke_every_30_seconds() {
+        let base = Instant::now();
+        let mut choker = choker_with_peers(2, &[1, 2, 3, 4], base);
+
+        // Peer 4 holds the only regular slot throughout
+        let mut rechoke = |secs: u64| {
+            let now = base + Duration::from_secs(secs);
+            choker.record_downloaded(&peer(4), 100, now);
+            choker.rechoke(now, false)
+        };
+
+        assert_eq!(rechoke(0), vec![(peer(1), false), (peer(4), false)]);
+        assert_eq!(rechoke(10), vec![]);
+        assert_eq!(rechoke(20), vec![]);
+        assert_eq!(rechoke(30), vec![(peer(1), true), (peer(2), false)]);
+        assert_eq!(rechoke(40), vec![]);
+        assert_eq!(rechoke(60), vec![(peer(2), true), (peer(3), false)]);
+        // Everyone's had a turn, so it goes back to whoever had theirs longest ago
+        assert_eq!(rechoke(90), vec![(peer(1), false), (peer(3), true)]);
+    }
+
+    #[test]
+    fn snubbed_peers_lose_their_regular_slots() {
+        let base = Instant::now();
+        // Nobody ever sends us anything, so peers 1 and 2 hold the regular slots on address alone
+        let mut choker = choker_with_peers(3, &[1, 2], base);
+        assert_eq!(choker.rechoke(base, false), vec![(peer(1), false), (peer(2), false)]);
+
+        let now = base + Duration::from_secs(50);
+        choker.add_peer(peer(3), now);
+        choker.set_interested(&peer(3), true);
+        assert_eq!(choker.rechoke(now, false), vec![(peer(3), false)]);
+
+        // A minute after connecting, peers 1 and 2 are snubbing us, so the newcomer takes a regular slot,
+        // and one of them is left with only the optimistic unchoke
+        let now = base + Duration::from_secs(60);
+        assert_eq!(choker.rechoke(now, false), vec![(peer(2), true)]);
+
+        // Snubbing only matters while we're downloading
+        let now = base + Duration::from_secs(70);
+        assert_eq!(choker.rechoke(now, true), vec![(peer(2), false)]);
+    }
+
+    #[test]
+    fn no_upload_slots_unchokes_nobody() {
+        let base = Instant::now();
+        let mut choker = choker_with_peers(0, &[1, 2, 3], base);
+        choker.record_downloaded(&peer(1), 100, base);
+
+        assert_eq!(choker.rechoke(base, false), vec![]);
+        assert_eq!(choker.rechoke(base + Duration::from_secs(30), true), vec![]);
+    }
+
+    #[test]
+    fn one_upload_slot_is_only_the_optimistic_unchoke() {
+        let base = Instant::now();
+        let mut choker = choker_with_peers(1, &[1, 2, 3], base);
+        // The fastest peer gets no special treatment, as there are no regular slots
+        choker.record_downloaded(&peer(3), 1000, base);
+
+        assert_eq!(choker.rechoke(base, false), vec![(peer(1), false)]);
+        assert_eq!(
+            choker.rechoke(base + Duration::from_secs(30), false),
+            vec![(peer(1), true), (peer(2), false)]
+        );
+    }
+
+    #[test]
+    fn removed_optimistic_peer_is_replaced_straight_away() {
+        let base = Instant::now();
+        let mut choker = choker_with_peers(1, &[1, 2], base);
+
+        assert_eq!(choker.rechoke(base, false), vec![(peer(1), false)]);
+        choker.remove_peer(&peer(1));
+        assert_eq!(choker.rechoke(base + RECHOKE_INTERVAL, false), vec![(peer(2), false)]);
+    }
+}
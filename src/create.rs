@@ -0,0 +1,370 @@
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use sha1::{Digest, Sha1};
+
+use crate::{
+    bencode,
+    metainfo::{DirectoryFileInfo, InfoDict, Metainfo, MetainfoFile, Sha1Hash},
+};
+
+const MIN_PIECE_LENGTH: u64 = 1 << 14;
+const MAX_PIECE_LENGTH: u64 = 1 << 24;
+// When picking a piece length automatically, aim for roughly this many pieces
+const TARGET_PIECE_COUNT: u64 = 1500;
+
+pub struct CreateOptions {
+    // Trackers, grouped into tiers
+    pub trackers: Vec<Vec<String>>,
+    // Picked automatically from the total size of the torrent if not set
+    pub piece_length: Option<u64>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    pub creation_date: bool,
+    pub private: bool,
+}
+
+// A file to be included in the torrent, and where its contents start within the torrent as a whole
+struct SourceFile {
+    disk_path: PathBuf,
+    // Relative to the torrent's root directory
+    path: Vec<String>,
+    length: u64,
+    offset: u64,
+}
+
+// Recursively collects every file under dir, sorted by path so that the output is reproducible
+fn walk_directory(root: &Path, dir: &Path, files: &mut Vec<(PathBuf, Vec<String>)>) -> Result<()> {
+    let mut entries = std::fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let disk_path = entry.path();
+
+        if disk_path.is_dir() {
+            walk_directory(root, &disk_path, files)?;
+        } else {
+            let path = disk_path
+                .strip_prefix(root)?
+                .components()
+                .map(|component| {
+                    component
+                        .as_os_str()
+                        .to_str()
+                        .map(String::from)
+                        .ok_or_else(|| anyhow!("Path is not valid UTF-8: {}", disk_path.display()))
+                })
+                .collect::<Result<Vec<String>>>()?;
+
+            files.push((disk_path, path));
+        }
+    }
+
+    Ok(())
+}
+
+fn collect_files(root: &Path) -> Result<Vec<SourceFile>> {
+    let mut paths = Vec::new();
+    walk_directory(root, root, &mut paths)?;
+
+    let mut offset = 0;
+    paths
+        .into_iter()
+        .map(|(disk_path, path)| {
+            let length = std::fs::metadata(&disk_path)?.len();
+            let file = SourceFile {
+                disk_path,
+                path,
+                length,
+                offset,
+            };
+
+            offset += length;
+            Ok(file)
+        })
+        .collect()
+}
+
+// The smallest power of two that keeps the piece count near TARGET_PIECE_COUNT, within sensible bounds
+fn pick_piece_length(total_length: u64) -> u64 {
+    (total_length / TARGET_PIECE_COUNT)
+        .next_power_of_two()
+        .clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)
+}
+
+// Hashes the pieces in piece_range. Pieces can span multiple files, so this reads
+// each piece into a buffer file by file before hashing it.
+fn hash_pieces(
+    files: &[SourceFile],
+    piece_length: u64,
+    total_length: u64,
+    piece_range: std::ops::Range<u64>,
+) -> Result<Vec<Sha1Hash>> {
+    let mut hashes = Vec::with_capacity((piece_range.end - piece_range.start) as usize);
+    let mut buffer = vec![0; piece_length as usize];
+
+    for piece_index in piece_range {
+        let piece_start = piece_index * piece_length;
+        let piece_end = (piece_start + piece_length).min(total_length);
+
+        for file in files {
+            let file_end = file.offset + file.length;
+            if file_end <= piece_start || file.offset >= piece_end || file.length == 0 {
+                continue;
+            }
+
+            let read_start = piece_start.max(file.offset);
+            let read_end = piece_end.min(file_end);
+
+            let mut f = File::open(&file.disk_path)?;
+            f.seek(SeekFrom::Start(read_start - file.offset))?;
+            f.read_exact(&mut buffer[(read_start - piece_start) as usize..(read_end - piece_start) as usize])?;
+        }
+
+        let mut hasher = Sha1::new();
+        hasher.update(&buffer[..(piece_end - piece_start) as usize]);
+        hashes.push(hasher.finalize()[..].try_into()?);
+    }
+
+    Ok(hashes)
+}
+
+// Splits the pieces into one contiguous run per available core and hashes the runs in parallel
+fn hash_pieces_parallel(files: &[SourceFile], piece_length: u64, total_length: u64) -> Result<Vec<Sha1Hash>> {
+    let piece_count = total_length.div_ceil(piece_length);
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get()) as u64;
+    let pieces_per_thread = piece_count.div_ceil(threads).max(1);
+
+    std::thread::scope(|scope| {
+        let handles = (0..piece_count)
+            .step_by(pieces_per_thread as usize)
+            .map(|start| {
+                let end = (start + pieces_per_thread).min(piece_count);
+                scope.spawn(move || hash_pieces(files, piece_length, total_length, start..end))
+            })
+            .collect::<Vec<_>>();
+
+        let mut hashes = Vec::with_capacity(piece_count as usize);
+        for handle in handles {
+            let run = handle
+                .join()
+                .map_err(|_| anyhow!("Piece hashing thread panicked"))??;
+            hashes.extend(run);
+        }
+
+        Ok(hashes)
+    })
+}
+
+// Builds a metainfo file for the file or directory at path, returning its bencoded bytes along with its info_hash
+pub fn create(path: &Path, options: &CreateOptions) -> Result<(Vec<u8>, Sha1Hash)> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow!("Invalid path to create a torrent from: {}", path.display()))?
+        .to_string();
+
+    let is_directory = path.is_dir();
+
+    let files = if is_directory {
+        collect_files(path)?
+    } else {
+        vec![SourceFile {
+            disk_path: path.to_path_buf(),
+            path: vec![name.clone()],
+            length: std::fs::metadata(path)?.len(),
+            offset: 0,
+        }]
+    };
+
+    if options.trackers.iter().flatten().next().is_none() {
+        return Err(anyhow!("At least one tracker is required"));
+    }
+
+    let total_length: u64 = files.iter().map(|file| file.length).sum();
+    if total_length == 0 {
+        return Err(anyhow!("Cannot create a torrent with no data in it"));
+    }
+
+    let piece_length = options.piece_length.unwrap_or_else(|| pick_piece_length(total_length));
+    if !piece_length.is_power_of_two() {
+        return Err(anyhow!("Piece length must be a power of two"));
+    }
+
+    let pieces = hash_pieces_parallel(&files, piece_length, total_length)?.concat();
+
+    let (length, dir_files) = if is_directory {
+        let dir_files = files
+            .into_iter()
+            .map(|file| DirectoryFileInfo {
+                path: file.path,
+                length: file.length,
+            })
+            .collect();
+
+        (None, Some(dir_files))
+    } else {
+        (Some(total_length), None)
+    };
+
+    let creation_date = if options.creation_date {
+        Some(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64)
+    } else {
+        None
+    };
+
+    // Clients that don't support announce-list fall back to announce, so that's set to the first tracker
+    let metainfo_file = MetainfoFile {
+        announce: options.trackers.iter().flatten().next().cloned(),
+        announce_list: if options.trackers.len() > 1 || options.trackers.iter().any(|tier| tier.len() > 1) {
+            Some(options.trackers.clone())
+        } else {
+            None
+        },
+        comment: options.comment.clone(),
+        created_by: options.created_by.clone(),
+        creation_date,
+        info: InfoDict {
+            name,
+            piece_length,
+            pieces: &pieces,
+            length,
+            files: dir_files,
+            private: if options.private { Some(1) } else { None },
+        },
+        nodes: None,
+    };
+
+    // Hashed separately from the file as a whole, so the parsed file's info_hash can be checked against it
+    let mut hasher = Sha1::new();
+    hasher.update(bencode::to_bytes(&metainfo_file.info)?);
+    let info_hash = hasher.finalize()[..].try_into()?;
+
+    Ok((bencode::to_bytes(&metainfo_file)?, info_hash))
+}
+
+// Creates a metainfo file and writes it to output, checking that it parses back to the expected torrent
+pub fn create_to_file(path: &Path, output: &Path, options: &CreateOptions) -> Result<Metainfo> {
+    let (bytes, info_hash) = create(path, options)?;
+    let metainfo = Metainfo::from_bytes(bytes.clone())?;
+
+    if metainfo.info_hash != info_hash {
+        return Err(anyhow!("Created metainfo file does not parse back to the same info dict"));
+    }
+
+    std::fs::write(output, bytes)?;
+    Ok(metainfo)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::Url;
+
+    use crate::metainfo::Info;
+
+    fn options() -> CreateOptions {
+        CreateOptions {
+            trackers: vec![vec!["http://tracker.example/announce".to_string()]],
+            piece_length: Some(MIN_PIECE_LENGTH),
+            comment: Some("A test torrent".to_string()),
+            created_by: None,
+            creation_date: false,
+            private: false,
+        }
+    }
+
+    // Data that doesn't repeat from piece to piece, so that misplaced pieces would be noticed
+    fn data(length: usize, seed: u8) -> Vec<u8> {
+        (0..length).map(|i| (i * 7 + i / 251) as u8 ^ seed).collect()
+    }
+
+    fn piece_hashes(data: &[u8], piece_length: usize) -> Vec<Sha1Hash> {
+        data.chunks(piece_length)
+            .map(|piece| Sha1::digest(piece)[..].try_into().unwrap())
+            .collect()
+    }
+
+    // The metainfo written to output must be the one returned, and must describe data, as read from source
+    fn assert_round_trips(metainfo: &Metainfo, source: &Path, output: &Path, data: &[u8]) {
+        let parsed = Metainfo::from_file(output).unwrap();
+
+        assert_eq!(parsed.info_hash, metainfo.info_hash);
+        assert_eq!(parsed.piece_length, MIN_PIECE_LENGTH);
+        assert_eq!(parsed.total_length, data.len());
+        assert_eq!(parsed.pieces, piece_hashes(data, MIN_PIECE_LENGTH as usize));
+        assert_eq!(parsed.comment.as_deref(), Some("A test torrent"));
+        assert_eq!(parsed.announce_list.concat(), vec![Url::parse("http://tracker.example/announce").unwrap()]);
+        assert!(!parsed.private);
+
+        // Encoding is canonical, so the same input always gives the same file
+        let (bytes, info_hash) = create(source, &options()).unwrap();
+        assert_eq!(info_hash, metainfo.info_hash);
+        assert_eq!(bytes, std::fs::read(output).unwrap());
+    }
+
+    #[test]
+    fn creates_single_file_torrent() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("file.bin");
+        let output = dir.path().join("file.torrent");
+        let contents = data(3 * MIN_PIECE_LENGTH as usize + 1000, 0);
+        std::fs::write(&source, &contents).unwrap();
+
+        let metainfo = create_to_file(&source, &output, &options()).unwrap();
+        assert_round_trips(&metainfo, &source, &output, &contents);
+
+        match metainfo.info {
+            Info::SingleFile(file_info) => {
+                assert_eq!(file_info.name, "file.bin");
+                assert_eq!(file_info.length, contents.len() as u64);
+            }
+            Info::Directory(_) => panic!("Expected a single-file torrent"),
+        }
+    }
+
+    #[test]
+    fn creates_directory_torrent() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("share");
+        let output = dir.path().join("share.torrent");
+
+        // Pieces span files, and one file is empty
+        let files: [(&[&str], Vec<u8>); 4] = [
+            (&["b.txt"], data(1000, 1)),
+            (&["a", "nested.bin"], data(2 * MIN_PIECE_LENGTH as usize + 5, 2)),
+            (&["a", "empty"], Vec::new()),
+            (&["c.bin"], data(MIN_PIECE_LENGTH as usize, 3)),
+        ];
+        for (path, contents) in &files {
+            let disk_path: PathBuf = std::iter::once(source.clone()).chain(path.iter().map(PathBuf::from)).collect();
+            std::fs::create_dir_all(disk_path.parent().unwrap()).unwrap();
+            std::fs::write(disk_path, contents).unwrap();
+        }
+
+        let metainfo = create_to_file(&source, &output, &options()).unwrap();
+
+        // Files are laid out sorted by path
+        let expected_order = [2, 1, 0, 3];
+        let contents: Vec<u8> = expected_order.iter().flat_map(|i| files[*i].1.clone()).collect();
+        assert_round_trips(&metainfo, &source, &output, &contents);
+
+        match metainfo.info {
+            Info::Directory(files_info) => {
+                assert_eq!(files_info.name, "share");
+                let paths: Vec<Vec<String>> = files_info.files.iter().map(|file| file.path.clone()).collect();
+                let expected_paths: Vec<Vec<String>> = expected_order
+                    .iter()
+                    .map(|i| files[*i].0.iter().map(|part| part.to_string()).collect())
+                    .collect();
+                assert_eq!(paths, expected_paths);
+            }
+            Info::SingleFile(_) => panic!("Expected a directory torrent"),
+        }
+    }
+}
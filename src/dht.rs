@@ -0,0 +1,961 @@
+use std::{
+    collections::{hash_map::Entry, BTreeMap, HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, Weak},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use tokio::{net::UdpSocket, sync::oneshot, task::JoinHandle};
+
+use crate::{
+    bencode,
+    metainfo::Sha1Hash,
+    net,
+    peer_list::{encode_compact_peer, parse_compact_peers},
+};
+
+// Node IDs share a keyspace with info hashes, so that a torrent's peers are stored on the nodes closest to its info hash
+pub type NodeId = [u8; 20];
+
+// Well-known routers used to join the DHT
+pub const DEFAULT_BOOTSTRAP_NODES: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+// The number of nodes in each bucket of the routing table, and the number of closest nodes a lookup converges on
+const K: usize = 8;
+// The number of queries a lookup sends out at once
+const ALPHA: usize = 3;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+// A node we haven't heard from in this long may have gone away, and is pinged before being kept over a new node
+const QUESTIONABLE_AFTER: Duration = Duration::from_secs(15 * 60);
+// A node that's failed to respond to this many queries in a row is replaced by the next new node we hear of
+const MAX_FAILURES: u32 = 2;
+// The secret tokens are made from is changed this often. Tokens made with the previous secret are still accepted.
+const TOKEN_ROTATION_INTERVAL: Duration = Duration::from_secs(5 * 60);
+// Peers that haven't re-announced themselves in this long are forgotten
+const PEER_EXPIRY: Duration = Duration::from_secs(30 * 60);
+// The most peers returned in a single get_peers response, so that it fits in one datagram
+const MAX_VALUES: usize = 50;
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+// How often we look up our own ID to keep the routing table fresh
+const REFRESH_INTERVAL: Duration = Duration::from_secs(15 * 60);
+// Saved nodes we last heard from longer ago than this have likely left the DHT, so aren't reloaded
+const MAX_SAVED_NODE_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+// KRPC error codes
+const GENERIC_ERROR: i64 = 201;
+const PROTOCOL_ERROR: i64 = 203;
+const METHOD_UNKNOWN: i64 = 204;
+
+// A KRPC message, which is either a query, a response or an error
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct KrpcMessage {
+    // The transaction ID, echoed back in the response to a query
+    t: ByteBuf,
+    // "q" for a query, "r" for a response or "e" for an error
+    y: String,
+    q: Option<String>,
+    a: Option<KrpcArgs>,
+    r: Option<KrpcResponse>,
+    e: Option<(i64, String)>,
+    // Read-only nodes (BEP 43) don't answer queries, so shouldn't be added to routing tables
+    ro: Option<u8>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct KrpcArgs {
+    id: ByteBuf,
+    target: Option<ByteBuf>,
+    info_hash: Option<ByteBuf>,
+    port: Option<u16>,
+    token: Option<ByteBuf>,
+    // Set to 1 if the port the announce_peer query came from should be used instead of port
+    implied_port: Option<u8>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct KrpcResponse {
+    id: ByteBuf,
+    // Compact node info: each node's ID followed by its address in compact form
+    nodes: Option<ByteBuf>,
+    nodes6: Option<ByteBuf>,
+    token: Option<ByteBuf>,
+    // Peers for the torrent, each in compact form
+    values: Option<Vec<ByteBuf>>,
+}
+
+// Our node ID and routing table, as saved to the state file between runs
+#[derive(Serialize, Deserialize)]
+struct SavedState {
+    id: ByteBuf,
+    nodes: Vec<SavedNode>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedNode {
+    id: ByteBuf,
+    // In compact form
+    addr: ByteBuf,
+    // Seconds since the UNIX epoch
+    last_seen: u64,
+}
+
+fn node_id(bytes: &[u8]) -> Result<NodeId> {
+    bytes.try_into().map_err(|_| anyhow!("Invalid node ID of length {}", bytes.len()))
+}
+
+fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut distance = [0u8; 20];
+    for i in 0..20 {
+        distance[i] = a[i] ^ b[i];
+    }
+    distance
+}
+
+fn parse_compact_nodes(bytes: &[u8], ipv6: bool) -> Vec<(NodeId, SocketAddr)> {
+    let addr_len = if ipv6 { 18 } else { 6 };
+
+    bytes
+        .chunks_exact(20 + addr_len)
+        .filter_map(|chunk| {
+            let (id, addr) = chunk.split_at(20);
+            Some((node_id(id).ok()?, *parse_compact_peers(addr, ipv6).first()?))
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    id: NodeId,
+    addr: SocketAddr,
+    last_seen: Instant,
+    // The number of queries in a row the node has failed to respond to
+    failures: u32,
+    // Whether we're waiting to hear back from a ping to decide whether to keep the node
+    ping_pending: bool,
+}
+
+impl Node {
+    fn new(id: NodeId, addr: SocketAddr) -> Self {
+        Self {
+            id,
+            addr,
+            last_seen: Instant::now(),
+            failures: 0,
+            ping_pending: false,
+        }
+    }
+
+    // Nodes that have been gone too long to be worth saving are None
+    fn save(&self, now: SystemTime) -> Option<SavedNode> {
+        let age = self.last_seen.elapsed();
+        if self.is_bad() || age >= MAX_SAVED_NODE_AGE {
+            return None;
+        }
+
+        let mut addr = Vec::new();
+        encode_compact_peer(&self.addr, &mut addr);
+
+        Some(SavedNode {
+            id: ByteBuf::from(self.id.to_vec()),
+            addr: ByteBuf::from(addr),
+            last_seen: (now - age).duration_since(UNIX_EPOCH).ok()?.as_secs(),
+        })
+    }
+
+    // Nodes that are invalid or too old to be worth reloading are None
+    fn load(saved: &SavedNode, now: SystemTime) -> Option<Self> {
+        let age = now
+            .duration_since(UNIX_EPOCH + Duration::from_secs(saved.last_seen))
+            .unwrap_or_default();
+        if age >= MAX_SAVED_NODE_AGE {
+            return None;
+        }
+
+        Some(Self {
+            // On some platforms, Instants can't go back further than when the machine booted, so nodes seen before then
+            // are treated as having just been seen. Any that have gone away will soon fail to respond to our queries.
+            last_seen: Instant::now().checked_sub(age).unwrap_or_else(Instant::now),
+            ..Self::new(
+                node_id(&saved.id).ok()?,
+                *parse_compact_peers(&saved.addr, saved.addr.len() == 18).first()?,
+            )
+        })
+    }
+
+    fn is_good(&self) -> bool {
+        self.failures == 0 && self.last_seen.elapsed() < QUESTIONABLE_AFTER
+    }
+
+    fn is_bad(&self) -> bool {
+        self.failures >= MAX_FAILURES
+    }
+}
+
+// The nodes we know of, bucketed by the length of the prefix their ID shares with ours.
+// We know of more nodes close to us than far away, which is what makes lookups converge.
+struct RoutingTable {
+    own_id: NodeId,
+    buckets: Vec<Vec<Node>>,
+}
+
+impl RoutingTable {
+    fn new(own_id: NodeId) -> Self {
+        Self {
+            own_id,
+            buckets: vec![Vec::new(); 160],
+        }
+    }
+
+    // Our own ID has no bucket
+    fn bucket_index(&self, id: &NodeId) -> Option<usize> {
+        let distance = distance(&self.own_id, id);
+        let leading_zeros = match distance.iter().position(|byte| *byte != 0) {
+            Some(i) => i * 8 + distance[i].leading_zeros() as usize,
+            None => return None,
+        };
+
+        Some(159 - leading_zeros)
+    }
+
+    // Records that we've heard from a node. Should its bucket be full of nodes that may still be alive, the
+    // least recently seen of them is returned; it's to be pinged, and replaced by the new node if it doesn't respond.
+    fn heard_from(&mut self, id: NodeId, addr: SocketAddr) -> Option<Node> {
+        let index = self.bucket_index(&id)?;
+        let bucket = &mut self.buckets[index];
+
+        if let Some(node) = bucket.iter_mut().find(|node| node.id == id) {
+            node.addr = addr;
+            node.last_seen = Instant::now();
+            node.failures = 0;
+            node.ping_pending = false;
+            return None;
+        }
+
+        if bucket.len() < K {
+            bucket.push(Node::new(id, addr));
+            return None;
+        }
+
+        if let Some(bad_node) = bucket.iter_mut().find(|node| node.is_bad()) {
+            *bad_node = Node::new(id, addr);
+            return None;
+        }
+
+        let questionable_node = bucket
+            .iter_mut()
+            .filter(|node| !node.is_good() && !node.ping_pending)
+            .min_by_key(|node| node.last_seen)?;
+        questionable_node.ping_pending = true;
+        Some(questionable_node.clone())
+    }
+
+    // Adds a node from a previous run, unless its bucket has already filled up
+    fn restore(&mut self, node: Node) {
+        if let Some(index) = self.bucket_index(&node.id) {
+            let bucket = &mut self.buckets[index];
+            if bucket.len() < K && !bucket.iter().any(|existing| existing.id == node.id) {
+                bucket.push(node);
+            }
+        }
+    }
+
+    // Replaces a node that's failed to respond to a ping with a new one
+    fn replace(&mut self, old_id: &NodeId, id: NodeId, addr: SocketAddr) {
+        if let Some(index) = self.bucket_index(old_id) {
+            if let Some(node) = self.buckets[index].iter_mut().find(|node| node.id == *old_id && node.failures > 0) {
+                *node = Node::new(id, addr);
+            }
+        }
+    }
+
+    fn failed(&mut self, addr: SocketAddr) {
+        for node in self.buckets.iter_mut().flatten().filter(|node| node.addr == addr) {
+            node.failures += 1;
+            node.ping_pending = false;
+        }
+    }
+
+    fn closest(&self, target: &NodeId, count: usize) -> Vec<Node> {
+        let mut nodes: Vec<&Node> = self.buckets.iter().flatten().filter(|node| !node.is_bad()).collect();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.into_iter().take(count).cloned().collect()
+    }
+
+    fn len(&self) -> usize {
+        self.buckets.iter().map(|bucket| bucket.len()).sum()
+    }
+}
+
+struct DhtState {
+    routing_table: RoutingTable,
+    // Queries awaiting a response, by transaction ID, along with the address each was sent to
+    transactions: HashMap<Vec<u8>, (SocketAddr, oneshot::Sender<Result<KrpcResponse>>)>,
+    next_transaction_id: u16,
+    // Peers that have announced themselves to us, and when they last did so
+    peers: HashMap<Sha1Hash, HashMap<SocketAddr, Instant>>,
+    token_secret: [u8; 20],
+    previous_token_secret: [u8; 20],
+    token_secret_rotated: Instant,
+    last_refresh: Instant,
+}
+
+impl DhtState {
+    // A token ties an announce_peer query to an earlier get_peers query from the same IP address
+    fn token(secret: &[u8; 20], ip: IpAddr) -> Vec<u8> {
+        let mut hasher = Sha1::new();
+        hasher.update(secret);
+        match ip {
+            IpAddr::V4(ip) => hasher.update(ip.octets()),
+            IpAddr::V6(ip) => hasher.update(ip.octets()),
+        }
+        hasher.finalize()[..8].to_vec()
+    }
+
+    fn token_is_valid(&self, token: &[u8], ip: IpAddr) -> bool {
+        token == Self::token(&self.token_secret, ip) || token == Self::token(&self.previous_token_secret, ip)
+    }
+}
+
+struct DhtInner {
+    id: NodeId,
+    socket: Arc<UdpSocket>,
+    local_addr: SocketAddr,
+    state: Mutex<DhtState>,
+    // Where our node ID and routing table are saved, if anywhere
+    state_file: Option<PathBuf>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl DhtInner {
+    fn save_state(&self) -> Result<()> {
+        let path = match &self.state_file {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let now = SystemTime::now();
+        let saved_state = SavedState {
+            id: ByteBuf::from(self.id.to_vec()),
+            nodes: self
+                .state
+                .lock()
+                .unwrap()
+                .routing_table
+                .buckets
+                .iter()
+                .flatten()
+                .filter_map(|node| node.save(now))
+                .collect(),
+        };
+
+        // Written to a temporary file first, so that being interrupted mid-write doesn't lose the previous state
+        let temp_path = path.with_extension("tmp");
+        std::fs::write(&temp_path, bencode::to_bytes(&saved_state)?)?;
+        std::fs::rename(temp_path, path)?;
+
+        Ok(())
+    }
+}
+
+impl Drop for DhtInner {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+
+        if let Err(e) = self.save_state() {
+            eprintln!("WARNING: Unable to save the DHT state: {}", e);
+        }
+    }
+}
+
+// Reads the node ID and routing table saved by a previous run
+fn load_state(path: &Path) -> Result<(NodeId, Vec<Node>)> {
+    let bytes = std::fs::read(path)?;
+    let saved_state: SavedState = bencode::from_bytes(&bytes)?;
+
+    let now = SystemTime::now();
+    let nodes = saved_state.nodes.iter().filter_map(|node| Node::load(node, now)).collect();
+
+    Ok((node_id(&saved_state.id)?, nodes))
+}
+
+#[derive(Clone, Copy)]
+enum LookupKind {
+    FindNode,
+    GetPeers,
+}
+
+#[derive(Default)]
+struct Candidate {
+    responded: bool,
+    token: Option<ByteBuf>,
+}
+
+#[derive(Default)]
+struct LookupResult {
+    peers: HashSet<SocketAddr>,
+    // The closest nodes to the target that responded, and the token (if any) each gave us
+    closest: Vec<(SocketAddr, Option<ByteBuf>)>,
+}
+
+// A node in the mainline DHT (BEP 5), which both answers queries from other nodes and looks up peers for torrents.
+// The node keeps running in the background for as long as a handle to it exists.
+// Given a state file, the node keeps its ID and routing table between runs, saving them periodically and once it stops.
+#[derive(Clone)]
+pub struct Dht(Arc<DhtInner>);
+
+impl Dht {
+    pub fn new(socket: UdpSocket, state_file: Option<PathBuf>) -> Result<Self> {
+        let local_addr = socket.local_addr()?;
+        let socket = Arc::new(socket);
+
+        // A missing state file just means this is our first run
+        let saved_state = match &state_file {
+            Some(path) if path.exists() => match load_state(path) {
+                Ok(saved_state) => Some(saved_state),
+                Err(e) => {
+                    eprintln!("WARNING: Ignoring invalid DHT state file {}: {}", path.display(), e);
+                    None
+                }
+            },
+            _ => None,
+        };
+        let (id, nodes) = saved_state.unwrap_or_else(|| (rand::thread_rng().gen(), Vec::new()));
+
+        let mut routing_table = RoutingTable::new(id);
+        for node in nodes {
+            routing_table.restore(node);
+        }
+
+        let state = DhtState {
+            routing_table,
+            transactions: HashMap::new(),
+            next_transaction_id: rand::thread_rng().gen(),
+            peers: HashMap::new(),
+            token_secret: rand::thread_rng().gen(),
+            previous_token_secret: rand::thread_rng().gen(),
+            token_secret_rotated: Instant::now(),
+            last_refresh: Instant::now(),
+        };
+
+        Ok(Self(Arc::new_cyclic(|inner: &Weak<DhtInner>| DhtInner {
+            id,
+            socket: socket.clone(),
+            local_addr,
+            state: Mutex::new(state),
+            state_file,
+            tasks: vec![
+                tokio::spawn(Self::receive_loop(socket, inner.clone())),
+                tokio::spawn(Self::maintenance_loop(inner.clone())),
+            ],
+        })))
+    }
+
+    fn state(&self) -> MutexGuard<'_, DhtState> {
+        self.0.state.lock().unwrap()
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.state().routing_table.len()
+    }
+
+    // Joins the DHT through the given nodes (as host:port), by looking up the nodes closest to our own ID
+    pub async fn bootstrap(&self, nodes: &[String]) {
+        let mut seeds = Vec::new();
+
+        for node in nodes {
+            match tokio::net::lookup_host(node).await {
+                Ok(addrs) => seeds.extend(addrs.filter(|addr| net::can_reach(&self.0.local_addr, addr))),
+                Err(e) => eprintln!("WARNING: Skipping DHT bootstrap node {}: {}", node, e),
+            }
+        }
+
+        self.lookup(self.0.id, LookupKind::FindNode, &seeds).await;
+    }
+
+    // Looks up the peers for a torrent. If a port is given, we announce ourselves as a peer on it as well.
+    pub async fn find_peers(&self, info_hash: &Sha1Hash, announce_port: Option<u16>) -> HashSet<SocketAddr> {
+        let mut result = self.lookup(*info_hash, LookupKind::GetPeers, &[]).await;
+
+        // Lookups never query ourselves, so any peers that have announced to us are added separately
+        if let Some(peers) = self.state().peers.get(info_hash) {
+            result.peers.extend(peers.keys());
+        }
+
+        if let Some(port) = announce_port {
+            let announces = result.closest.iter().filter_map(|(addr, token)| {
+                let args = KrpcArgs {
+                    info_hash: Some(ByteBuf::from(info_hash.to_vec())),
+                    port: Some(port),
+                    token: Some(token.clone()?),
+                    implied_port: Some(0),
+                    ..self.args()
+                };
+                Some(self.query(*addr, "announce_peer", args))
+            });
+
+            // A node refusing our announce doesn't make the peers we found any less useful
+            futures::future::join_all(announces).await;
+        }
+
+        result.peers
+    }
+
+    fn args(&self) -> KrpcArgs {
+        KrpcArgs {
+            id: ByteBuf::from(self.0.id.to_vec()),
+            ..KrpcArgs::default()
+        }
+    }
+
+    async fn send(&self, message: &KrpcMessage, addr: SocketAddr) -> Result<()> {
+        self.0
+            .socket
+            .send_to(&bencode::to_bytes(message)?, net::addr_for_socket(addr, &self.0.local_addr))
+            .await?;
+        Ok(())
+    }
+
+    async fn query(&self, addr: SocketAddr, method: &str, args: KrpcArgs) -> Result<KrpcResponse> {
+        let (response_tx, response_rx) = oneshot::channel();
+
+        let transaction_id = {
+            let mut state = self.state();
+            state.next_transaction_id = state.next_transaction_id.wrapping_add(1);
+            let transaction_id = state.next_transaction_id.to_be_bytes().to_vec();
+            state.transactions.insert(transaction_id.clone(), (addr, response_tx));
+            transaction_id
+        };
+
+        let message = KrpcMessage {
+            t: ByteBuf::from(transaction_id.clone()),
+            y: "q".to_string(),
+            q: Some(method.to_string()),
+            a: Some(args),
+            ..KrpcMessage::default()
+        };
+
+        let response = async {
+            self.send(&message, addr).await?;
+            tokio::time::timeout(QUERY_TIMEOUT, response_rx)
+                .await
+                .map_err(|_| anyhow!("{} query to {} timed out", method, addr))??
+        }
+        .await;
+
+        if response.is_err() {
+            let mut state = self.state();
+            state.transactions.remove(&transaction_id);
+            state.routing_table.failed(addr);
+        }
+
+        response
+    }
+
+    async fn ping(&self, addr: SocketAddr) -> Result<KrpcResponse> {
+        self.query(addr, "ping", self.args()).await
+    }
+
+    fn heard_from(&self, id: NodeId, addr: SocketAddr) {
+        let questionable_node = self.state().routing_table.heard_from(id, addr);
+
+        if let Some(questionable_node) = questionable_node {
+            let dht = self.clone();
+            tokio::spawn(async move {
+                if dht.ping(questionable_node.addr).await.is_err() {
+                    dht.state().routing_table.replace(&questionable_node.id, id, addr);
+                }
+            });
+        }
+    }
+
+    // Iteratively queries the nodes closest to the target, converging on the K closest nodes in the whole DHT.
+    // Seeds are nodes whose IDs we don't know yet, such as bootstrap nodes; they're treated as being as far away as possible.
+    async fn lookup(&self, target: NodeId, kind: LookupKind, seeds: &[SocketAddr]) -> LookupResult {
+        let mut candidates: BTreeMap<(NodeId, SocketAddr), Candidate> = BTreeMap::new();
+        let mut queried = HashSet::new();
+        let mut peers = HashSet::new();
+
+        for node in self.state().routing_table.closest(&target, K) {
+            candidates.insert((distance(&node.id, &target), node.addr), Candidate::default());
+        }
+        for seed in seeds {
+            candidates.insert(([0xff; 20], *seed), Candidate::default());
+        }
+
+        loop {
+            // Nodes that fail to respond are dropped, so we're done once the K closest nodes have all responded
+            let batch: Vec<(NodeId, SocketAddr)> = candidates
+                .iter()
+                .take(K)
+                .filter(|(_, candidate)| !candidate.responded)
+                .map(|(key, _)| *key)
+                .take(ALPHA)
+                .collect();
+
+            if batch.is_empty() {
+                break;
+            }
+
+            let queries = batch.iter().map(|(_, addr)| {
+                queried.insert(*addr);
+
+                let (method, args) = match kind {
+                    LookupKind::FindNode => ("find_node", KrpcArgs {
+                        target: Some(ByteBuf::from(target.to_vec())),
+                        ..self.args()
+                    }),
+                    LookupKind::GetPeers => ("get_peers", KrpcArgs {
+                        info_hash: Some(ByteBuf::from(target.to_vec())),
+                        ..self.args()
+                    }),
+                };
+
+                self.query(*addr, method, args)
+            });
+            let responses = futures::future::join_all(queries).await;
+
+            for (key, response) in batch.into_iter().zip(responses) {
+                let response = match response {
+                    Ok(response) => response,
+                    Err(_) => {
+                        candidates.remove(&key);
+                        continue;
+                    }
+                };
+
+                for value in response.values.iter().flatten() {
+                    peers.extend(parse_compact_peers(value, value.len() == 18));
+                }
+
+                let nodes = response.nodes.iter().flat_map(|nodes| parse_compact_nodes(nodes, false));
+                let nodes6 = response.nodes6.iter().flat_map(|nodes| parse_compact_nodes(nodes, true));
+                for (id, addr) in nodes.chain(nodes6) {
+                    if id != self.0.id && !queried.contains(&addr) && net::can_reach(&self.0.local_addr, &addr) {
+                        candidates.entry((distance(&id, &target), addr)).or_default();
+                    }
+                }
+
+                if let Some(candidate) = candidates.get_mut(&key) {
+                    candidate.responded = true;
+                    candidate.token = response.token;
+                }
+            }
+        }
+
+        LookupResult {
+            peers,
+            closest: candidates
+                .into_iter()
+                .filter(|(_, candidate)| candidate.responded)
+                .take(K)
+                .map(|((_, addr), candidate)| (addr, candidate.token))
+                .collect(),
+        }
+    }
+
+    async fn receive_loop(socket: Arc<UdpSocket>, inner: Weak<DhtInner>) {
+        let mut buf = vec![0u8; 65536];
+
+        loop {
+            // Errors here are usually ICMP port unreachable messages from nodes that have gone away
+            let (len, from) = match socket.recv_from(&mut buf).await {
+                Ok((len, from)) => (len, net::canonical_addr(from)),
+                Err(_) => continue,
+            };
+
+            let dht = match inner.upgrade() {
+                Some(inner) => Dht(inner),
+                None => break,
+            };
+
+            // Anything that isn't valid KRPC is ignored
+            if let Ok(message) = bencode::from_bytes::<KrpcMessage>(&buf[..len]) {
+                match message.y.as_str() {
+                    "q" => {
+                        let _ = dht.handle_query(message, from).await;
+                    }
+                    "r" | "e" => dht.handle_response(message, from),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    async fn maintenance_loop(inner: Weak<DhtInner>) {
+        let mut interval = tokio::time::interval(MAINTENANCE_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            let dht = match inner.upgrade() {
+                Some(inner) => Dht(inner),
+                None => break,
+            };
+
+            let refresh = {
+                let mut state = dht.state();
+
+                if state.token_secret_rotated.elapsed() >= TOKEN_ROTATION_INTERVAL {
+                    state.previous_token_secret = state.token_secret;
+                    state.token_secret = rand::thread_rng().gen();
+                    state.token_secret_rotated = Instant::now();
+                }
+
+                for peers in state.peers.values_mut() {
+                    peers.retain(|_, announced| announced.elapsed() < PEER_EXPIRY);
+                }
+                state.peers.retain(|_, peers| !peers.is_empty());
+
+                let refresh = state.last_refresh.elapsed() >= REFRESH_INTERVAL;
+                if refresh {
+                    state.last_refresh = Instant::now();
+                }
+                refresh
+            };
+
+            if refresh {
+                dht.lookup(dht.0.id, LookupKind::FindNode, &[]).await;
+            }
+
+            // Saved regularly as well as on shutdown, in case we're killed without getting the chance
+            if let Err(e) = dht.0.save_state() {
+                eprintln!("WARNING: Unable to save the DHT state: {}", e);
+            }
+        }
+    }
+
+    fn handle_response(&self, message: KrpcMessage, from: SocketAddr) {
+        // Responses from anyone other than the node we queried are ignored
+        let response_tx = match self.state().transactions.entry(message.t.to_vec()) {
+            Entry::Occupied(entry) if entry.get().0 == from => entry.remove().1,
+            _ => return,
+        };
+
+        let response = match (message.r, message.e) {
+            (Some(response), _) if message.y == "r" => node_id(&response.id).map(|id| {
+                self.heard_from(id, from);
+                response
+            }),
+            (_, Some((code, error))) => Err(anyhow!("{} responded with error {}: {}", from, code, error)),
+            _ => Err(anyhow!("Invalid response from {}", from)),
+        };
+
+        // The query may have timed out in the meantime
+        let _ = response_tx.send(response);
+    }
+
+    async fn handle_query(&self, message: KrpcMessage, from: SocketAddr) -> Result<()> {
+        let reply = match self.respond(&message, from) {
+            Ok(response) => KrpcMessage {
+                t: message.t,
+                y: "r".to_string(),
+                r: Some(response),
+                ..KrpcMessage::default()
+            },
+            Err((code, error)) => KrpcMessage {
+                t: message.t,
+                y: "e".to_string(),
+                e: Some((code, error.to_string())),
+                ..KrpcMessage::default()
+            },
+        };
+
+        self.send(&reply, from).await
+    }
+
+    fn respond(&self, message: &KrpcMessage, from: SocketAddr) -> Result<KrpcResponse, (i64, &'static str)> {
+        let args = message.a.as_ref().ok_or((PROTOCOL_ERROR, "Missing arguments"))?;
+        let id = node_id(&args.id).map_err(|_| (PROTOCOL_ERROR, "Invalid node ID"))?;
+
+        if message.ro != Some(1) {
+            self.heard_from(id, from);
+        }
+
+        let mut response = KrpcResponse {
+            id: ByteBuf::from(self.0.id.to_vec()),
+            ..KrpcResponse::default()
+        };
+
+        let info_hash = || -> Result<Sha1Hash, (i64, &'static str)> {
+            node_id(args.info_hash.as_deref().ok_or((PROTOCOL_ERROR, "Missing info_hash"))?)
+                .map_err(|_| (PROTOCOL_ERROR, "Invalid info_hash"))
+        };
+
+        let mut state = self.state();
+
+        match message.q.as_deref() {
+            Some("ping") => {}
+            Some("find_node") => {
+                let target = node_id(args.target.as_deref().ok_or((PROTOCOL_ERROR, "Missing target"))?)
+                    .map_err(|_| (PROTOCOL_ERROR, "Invalid target"))?;
+                Self::add_nodes(&mut response, &state.routing_table.closest(&target, K));
+            }
+            Some("get_peers") => {
+                let info_hash = info_hash()?;
+                response.token = Some(ByteBuf::from(DhtState::token(&state.token_secret, from.ip())));
+
+                match state.peers.get(&info_hash) {
+                    Some(peers) => {
+                        response.values = Some(
+                            peers
+                                .keys()
+                                .take(MAX_VALUES)
+                                .map(|peer| {
+                                    let mut value = Vec::new();
+                                    encode_compact_peer(peer, &mut value);
+                                    ByteBuf::from(value)
+                                })
+                                .collect(),
+                        )
+                    }
+                    None => Self::add_nodes(&mut response, &state.routing_table.closest(&info_hash, K)),
+                }
+            }
+            Some("announce_peer") => {
+                let info_hash = info_hash()?;
+                let token = args.token.as_deref().ok_or((PROTOCOL_ERROR, "Missing token"))?;
+                if !state.token_is_valid(token, from.ip()) {
+                    return Err((PROTOCOL_ERROR, "Invalid token"));
+                }
+
+                let port = match (args.implied_port, args.port) {
+                    (Some(1), _) => from.port(),
+                    (_, Some(port)) if port != 0 => port,
+                    _ => return Err((GENERIC_ERROR, "Invalid port")),
+                };
+
+                state
+                    .peers
+                    .entry(info_hash)
+                    .or_default()
+                    .insert(SocketAddr::new(from.ip(), port), Instant::now());
+            }
+            _ => return Err((METHOD_UNKNOWN, "Method Unknown")),
+        }
+
+        Ok(response)
+    }
+
+    // Adds the given nodes to a response in compact node info form
+    fn add_nodes(response: &mut KrpcResponse, nodes: &[Node]) {
+        // The nodes key is required, even if we know of no nodes to put in it
+        response.nodes.get_or_insert_with(ByteBuf::new);
+
+        for node in nodes {
+            let compact_nodes = if node.addr.is_ipv4() { &mut response.nodes } else { &mut response.nodes6 };
+            let compact_nodes = compact_nodes.get_or_insert_with(ByteBuf::new);
+            compact_nodes.extend(node.id);
+            encode_compact_peer(&node.addr, compact_nodes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A handful of nodes on localhost, each having joined the DHT through the first
+    async fn swarm(size: usize) -> Vec<Dht> {
+        let mut nodes = Vec::new();
+        for _ in 0..size {
+            nodes.push(Dht::new(UdpSocket::bind("127.0.0.1:0").await.unwrap(), None).unwrap());
+        }
+
+        let seed = vec![nodes[0].0.local_addr.to_string()];
+        for node in &nodes[1..] {
+            node.bootstrap(&seed).await;
+        }
+        // Joining a second time lets the earliest nodes learn of those that joined after them
+        for node in &nodes[1..] {
+            node.bootstrap(&seed).await;
+        }
+
+        nodes
+    }
+
+    #[tokio::test]
+    async fn announced_peer_is_found_by_another_node() {
+        let nodes = swarm(6).await;
+        for node in &nodes {
+            assert!(node.node_count() > 0);
+        }
+
+        let info_hash: Sha1Hash = rand::thread_rng().gen();
+        assert!(nodes[1].find_peers(&info_hash, Some(6881)).await.is_empty());
+
+        // The announce only succeeds with the tokens handed out by get_peers, so a node other than the announcer
+        // storing the peer shows they were accepted
+        let peer = SocketAddr::from(([127, 0, 0, 1], 6881));
+        let others = || nodes.iter().enumerate().filter(|(i, _)| *i != 1).map(|(_, node)| node);
+        assert!(others().any(|node| node.state().peers.get(&info_hash).is_some_and(|peers| peers.contains_key(&peer))));
+
+        for node in others() {
+            assert_eq!(node.find_peers(&info_hash, None).await, HashSet::from([peer]));
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_id_and_routing_table_between_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_file = dir.path().join(".downpour-dht");
+
+        let nodes = swarm(4).await;
+        let (id, node_count) = {
+            let node = Dht::new(UdpSocket::bind("127.0.0.1:0").await.unwrap(), Some(state_file.clone())).unwrap();
+            node.bootstrap(&[nodes[0].0.local_addr.to_string()]).await;
+            (node.0.id, node.node_count())
+        };
+        assert_eq!(node_count, 4);
+
+        // Dropping the last handle saved the state
+        let node = Dht::new(UdpSocket::bind("127.0.0.1:0").await.unwrap(), Some(state_file)).unwrap();
+        assert_eq!(node.0.id, id);
+        assert_eq!(node.node_count(), node_count);
+    }
+
+    #[test]
+    fn loads_nodes_older_than_the_clock_allows() {
+        let now = SystemTime::now();
+        let saved = |age: Duration| SavedNode {
+            id: ByteBuf::from(vec![1; 20]),
+            addr: ByteBuf::from(vec![127, 0, 0, 1, 0x1a, 0xe1]),
+            last_seen: (now - age).duration_since(UNIX_EPOCH).unwrap().as_secs(),
+        };
+
+        // Likely to be longer ago than the machine booted, which is as far back as an Instant can go on some platforms
+        let node = Node::load(&saved(MAX_SAVED_NODE_AGE - Duration::from_secs(60)), now).unwrap();
+        assert_eq!(node.addr, SocketAddr::from(([127, 0, 0, 1], 6881)));
+
+        assert!(Node::load(&saved(MAX_SAVED_NODE_AGE), now).is_none());
+    }
+
+    #[tokio::test]
+    async fn announce_with_invalid_token_is_refused() {
+        let nodes = swarm(2).await;
+        let info_hash: Sha1Hash = rand::thread_rng().gen();
+
+        let args = KrpcArgs {
+            info_hash: Some(ByteBuf::from(info_hash.to_vec())),
+            port: Some(6881),
+            token: Some(ByteBuf::from(b"not a token".to_vec())),
+            implied_port: Some(0),
+            ..nodes[1].args()
+        };
+        let response = nodes[1].query(nodes[0].0.local_addr, "announce_peer", args).await;
+
+        assert!(response.unwrap_err().to_string().contains("Invalid token"));
+        assert!(!nodes[0].state().peers.contains_key(&info_hash));
+    }
+}
@@ -1,90 +1,132 @@
-use std::{io::{Cursor, SeekFrom}, net::SocketAddr, collections::HashMap, path::{PathBuf, Path}};
+use std::{io::{Cursor, SeekFrom}, net::SocketAddr, collections::{HashMap, VecDeque, hash_map::Entry}, path::{PathBuf, Path}, sync::{Arc, atomic::Ordering}, time::Instant};
 
 use anyhow::{anyhow, Result};
 use binread::BinRead;
 use binwrite::BinWrite;
 use boolvec::BoolVec;
 use futures::{stream::FuturesUnordered, StreamExt, Future};
-use tokio::{io::{AsyncWriteExt, AsyncSeekExt}, net::TcpStream, sync::mpsc, fs::File};
+use sha1::{Digest, Sha1};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt, AsyncSeekExt}, net::TcpStream, sync::{mpsc::{self, error::TrySendError}, watch}, fs::File, task::JoinHandle};
 
 use crate::{
+    announcer::TransferStats,
+    choker::{Choker, RECHOKE_INTERVAL},
+    listener::IncomingPeer,
+    extensions::{has_extension_bit, set_extension_bit, Extension, ExtensionRegistry, EXTENSION_TICK_INTERVAL, MAX_QUEUED_REQUESTS},
+    pex::{PeerExchange, PEX_FLAG_REACHABLE, PEX_FLAG_SEED},
     metainfo::{Metainfo, Sha1Hash, Info},
     peer_list::PeerList,
     ClientConfig, PeerID,
 };
 
 const BLOCK_LENGTH: u32 = 1 << 14; // in bytes. 1 << 14 == 16KB.
+// The largest block we'll send in response to a request. Peers almost always ask for BLOCK_LENGTH.
+const MAX_REQUEST_LENGTH: u32 = 1 << 17;
 
 #[derive(BinRead, BinWrite, Debug)]
 #[br(big)]
 #[binwrite(big)]
-struct Handshake {
-    pstrlen: u8,
+pub struct Handshake {
+    pub pstrlen: u8,
     #[br(count=pstrlen)]
-    pstr: Vec<u8>,
-    reserved: [u8; 8],
-    info_hash: Sha1Hash,
-    peer_id: PeerID,
+    pub pstr: Vec<u8>,
+    pub reserved: [u8; 8],
+    pub info_hash: Sha1Hash,
+    pub peer_id: PeerID,
+}
+
+// Support for the fast extension (BEP 6) is flagged by the third bit from the right of the reserved bytes
+fn set_fast_extension_bit(reserved: &mut [u8; 8]) {
+    reserved[7] |= 0x04;
+}
+
+fn has_fast_extension_bit(reserved: &[u8; 8]) -> bool {
+    reserved[7] & 0x04 != 0
 }
 
 #[derive(BinRead, BinWrite, Debug)]
 #[br(big)]
 #[binwrite(big)]
-struct PacketHeader {
-    len: u32,
-    id: u8,
+pub struct PacketHeader {
+    pub len: u32,
+    pub id: u8,
 }
 
 #[derive(BinRead, BinWrite, Debug)]
 #[br(big)]
 #[binwrite(big)]
-struct HavePacket {
-    header: PacketHeader,
-    index: u32,
+pub struct HavePacket {
+    pub header: PacketHeader,
+    pub index: u32,
 }
 
 #[derive(BinRead, BinWrite, Debug)]
 #[br(big)]
 #[binwrite(big)]
-struct BitfieldPacket {
-    header: PacketHeader,
+pub struct BitfieldPacket {
+    pub header: PacketHeader,
     #[br(count = header.len - 1)]
-    bitfield: Vec<u8>,
+    pub bitfield: Vec<u8>,
 }
 
 #[derive(BinRead, BinWrite, Debug)]
 #[br(big)]
 #[binwrite(big)]
-struct RequestPacket {
-    header: PacketHeader,
-    index: u32,
-    begin: u32,
-    length: u32,
+pub struct RequestPacket {
+    pub header: PacketHeader,
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
 }
 
 #[derive(BinRead, BinWrite, Debug)]
 #[br(big)]
 #[binwrite(big)]
-struct PiecePacket {
-    header: PacketHeader,
-    index: u32,
-    begin: u32,
+pub struct PiecePacket {
+    pub header: PacketHeader,
+    pub index: u32,
+    pub begin: u32,
     #[br(count = header.len - 9)]
-    block: Vec<u8>,
+    pub block: Vec<u8>,
 }
 
 #[derive(BinRead, BinWrite, Debug)]
 #[br(big)]
 #[binwrite(big)]
-struct CancelPacket {
-    header: PacketHeader,
-    index: u32,
-    begin: u32,
-    length: u32,
+pub struct CancelPacket {
+    pub header: PacketHeader,
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+// Messages added by the extension protocol (BEP 10). extended_id 0 is the extended handshake;
+// any other ID is one of the extensions named in that handshake.
+#[derive(BinRead, BinWrite, Debug)]
+#[br(big)]
+#[binwrite(big)]
+pub struct ExtendedPacket {
+    pub header: PacketHeader,
+    pub extended_id: u8,
+    #[br(count = header.len - 2)]
+    pub payload: Vec<u8>,
+}
+
+impl ExtendedPacket {
+    pub fn new(extended_id: u8, payload: Vec<u8>) -> Self {
+        Self {
+            header: PacketHeader {
+                len: payload.len() as u32 + 2,
+                id: 20,
+            },
+            extended_id,
+            payload,
+        }
+    }
 }
 
 #[derive(Debug)]
-enum Packet {
+pub enum Packet {
     KeepAlive,
     Choke,
     Unchoke,
@@ -95,6 +137,14 @@ enum Packet {
     Request(RequestPacket),
     Piece(PiecePacket),
     Cancel(CancelPacket),
+    // The fast extension's messages (BEP 6). Reject carries the same fields as the request it's turning down.
+    // Suggest and AllowedFast name a piece, but we've no use for either.
+    Suggest,
+    HaveAll,
+    HaveNone,
+    Reject(RequestPacket),
+    AllowedFast,
+    Extended(ExtendedPacket),
 }
 
 fn parse_packet(packet_buf: &[u8]) -> Result<Packet> {
@@ -125,11 +175,23 @@ fn parse_packet(packet_buf: &[u8]) -> Result<Packet> {
         8 => Ok(Packet::Cancel(CancelPacket::read(&mut Cursor::new(
             packet_buf,
         ))?)),
+        13 => Ok(Packet::Suggest),
+        14 => Ok(Packet::HaveAll),
+        15 => Ok(Packet::HaveNone),
+        16 => Ok(Packet::Reject(RequestPacket::read(&mut Cursor::new(
+            packet_buf,
+        ))?)),
+        17 => Ok(Packet::AllowedFast),
+        // Extended messages must at least have room for their extended message ID
+        20 if packet_header.len < 2 => Err(anyhow!("Extended packet too short to hold an extended message ID")),
+        20 => Ok(Packet::Extended(ExtendedPacket::read(&mut Cursor::new(
+            packet_buf,
+        ))?)),
         _ => Err(anyhow!("Unknown packet with ID {}", packet_header.id)),
     }
 }
 
-fn read_packets(data_buf: &mut Vec<u8>) -> Result<Vec<Packet>> {
+pub fn read_packets(data_buf: &mut Vec<u8>) -> Result<Vec<Packet>> {
     let mut packets = Vec::new();
 
     // Do we have a full packet present in the buffer?
@@ -163,80 +225,198 @@ struct PeerPacket {
 #[derive(Debug)]
 enum PeerOutgoingMessage {
     RequestBlock { index: u32, begin: u32, length: u32},
+    // Only sent on if the peer supports the fast extension; without it, turned down requests simply go unanswered
+    RejectRequest { index: u32, begin: u32, length: u32 },
+    Have { index: u32 },
+    // Choking a peer throws away its outstanding requests, which are listed here,
+    // so that peers with the fast extension can be told which
+    Choke { rejected: Vec<(u32, u32, u32)> },
+    Unchoke,
+}
+
+// A block we're sending in answer to a request. These go down their own bounded channel, separate from every other
+// message, so that the manager can hold off reading more blocks from disk for a peer that's slow to take them,
+// without that holding up anything else the peer's sent.
+#[derive(Debug)]
+struct OutgoingBlock {
+    index: u32,
+    begin: u32,
+    block: Vec<u8>,
+}
+
+// Packs which pieces we have into a bitfield, with the first piece in the highest bit of the first byte
+fn encode_bitfield(have: &[bool]) -> Vec<u8> {
+    let mut bitfield = vec![0u8; have.len().div_ceil(8)];
+    for (i, _) in have.iter().enumerate().filter(|(_, have)| **have) {
+        bitfield[i / 8] |= 0x80 >> (i % 8);
+    }
+    bitfield
+}
+
+// The handshake always comes first on a connection: pstrlen, the 19-byte protocol string, reserved bytes, info hash and peer ID
+const HANDSHAKE_LENGTH: usize = 68;
+
+pub async fn write_handshake(
+    stream: &mut TcpStream,
+    info_hash: &Sha1Hash,
+    client_config: &ClientConfig,
+    reserved: [u8; 8],
+) -> Result<()> {
+    let mut bytes = vec![];
+    Handshake {
+        pstrlen: 19,
+        pstr: b"BitTorrent protocol".to_vec(),
+        reserved,
+        info_hash: *info_hash,
+        peer_id: client_config.peer_id,
+    }
+    .write(&mut bytes)?;
+    stream.write_all(&bytes).await?;
+
+    Ok(())
+}
+
+pub async fn read_handshake(stream: &mut TcpStream, client_config: &ClientConfig) -> Result<Handshake> {
+    let mut buf = vec![0u8; HANDSHAKE_LENGTH];
+    tokio::time::timeout(client_config.timeout, stream.read_exact(&mut buf)).await??;
+
+    let handshake = Handshake::read(&mut Cursor::new(buf))?;
+
+    if handshake.pstrlen != 19 || handshake.pstr != b"BitTorrent protocol" {
+        return Err(anyhow!("Invalid handshake received from peer"));
+    }
+
+    Ok(handshake)
+}
+
+// Connects to a peer and exchanges handshakes with it, advertising the extensions flagged in reserved
+pub async fn connect_to_peer(
+    peer: SocketAddr,
+    info_hash: &Sha1Hash,
+    client_config: &ClientConfig,
+    reserved: [u8; 8],
+) -> Result<(TcpStream, Handshake)> {
+    let mut stream =
+        tokio::time::timeout(client_config.timeout, TcpStream::connect(peer)).await??;
+
+    // Let's be polite, and handshake!
+    write_handshake(&mut stream, info_hash, client_config, reserved).await?;
+    let handshake_reply = read_handshake(&mut stream, client_config).await?;
+
+    if handshake_reply.info_hash != *info_hash {
+        return Err(anyhow!("Invalid handshake received from peer"));
+    }
+
+    Ok((stream, handshake_reply))
+}
+
+// How a connection to a peer came about
+pub enum PeerConnection {
+    // We're to connect to the peer ourselves
+    Outgoing,
+    // The peer connected to us, and has sent its handshake, which is yet to be answered
+    Incoming(TcpStream, Handshake),
+}
+
+// A peer thread's ends of its channels to and from the manager
+struct ManagerChannels {
+    tx: mpsc::Sender<PeerPacket>,
+    rx: mpsc::UnboundedReceiver<PeerOutgoingMessage>,
+    blocks: mpsc::Receiver<OutgoingBlock>,
 }
 
 async fn peer_thread(
     peer: SocketAddr,
+    connection: PeerConnection,
     client_config: ClientConfig,
     metainfo: Metainfo,
-    manager_tx: mpsc::Sender<PeerPacket>,
-    mut manager_rx: mpsc::Receiver<PeerOutgoingMessage>,
+    manager: ManagerChannels,
+    extensions: Vec<Box<dyn Extension>>,
+    // The pieces we had when the thread was started. The manager tells us of any we finish after that.
+    have: Vec<bool>,
 ) -> Result<()> {
     {
-        let mut stream =
-            tokio::time::timeout(client_config.timeout, TcpStream::connect(peer)).await??;
-
-        // Let's be polite, and handshake!
-        let mut bytes = vec![];
-        Handshake {
-            pstrlen: 19,
-            pstr: b"BitTorrent protocol".to_vec(),
-            reserved: [0u8; 8],
-            info_hash: metainfo.info_hash,
-            peer_id: client_config.peer_id,
-        }
-        .write(&mut bytes)?;
-        stream.write_all(&bytes).await?;
-
-        stream.readable().await?;
-        let mut buf = [0u8; 4096];
-        let buf_len = stream.try_read(&mut buf)?;
-
-        let mut handshake_cursor = Cursor::new(buf);
-        let handshake_reply = Handshake::read(&mut handshake_cursor)?;
-
-        if handshake_reply.pstrlen != 19
-            || handshake_reply.pstr != b"BitTorrent protocol"
-            || handshake_reply.info_hash != metainfo.info_hash
-        {
-            return Err(anyhow!("Invalid handshake received from peer"));
-        }
+        let ManagerChannels { tx: manager_tx, rx: mut manager_rx, blocks: mut block_rx } = manager;
+
+        let mut reserved = [0u8; 8];
+        set_extension_bit(&mut reserved);
+        set_fast_extension_bit(&mut reserved);
+
+        let (mut stream, handshake_reply) = match connection {
+            PeerConnection::Outgoing => connect_to_peer(
+                peer,
+                &metainfo.info_hash,
+                &client_config,
+                reserved,
+            ).await?,
+            PeerConnection::Incoming(mut stream, handshake) => {
+                write_handshake(&mut stream, &metainfo.info_hash, &client_config, reserved).await?;
+                (stream, handshake)
+            },
+        };
 
         match std::str::from_utf8(&handshake_reply.peer_id) {
             Ok(str) => println!("Connection established to {}", str),
             Err(_) => println!("Connection established to {:?}", &handshake_reply.peer_id),
         }
 
-        // Immediately unchoke and register our interest in this peer
+        let fast_extension = has_fast_extension_bit(&handshake_reply.reserved);
         let mut bytes = vec![];
 
-        PacketHeader {
-            len: 1,
-            id: 1, // unchoke
+        // Tell the peer which pieces we have. This has to be the first message after the handshake.
+        if fast_extension && have.iter().all(|have| *have) {
+            PacketHeader { len: 1, id: 14 }.write(&mut bytes)?; // have all
+        } else if fast_extension && !have.contains(&true) {
+            PacketHeader { len: 1, id: 15 }.write(&mut bytes)?; // have none
+        } else if have.contains(&true) {
+            let bitfield = encode_bitfield(&have);
+            BitfieldPacket {
+                header: PacketHeader { len: 1 + bitfield.len() as u32, id: 5 },
+                bitfield,
+            }
+            .write(&mut bytes)?;
         }
-        .write(&mut bytes)?;
 
+        // Immediately register our interest in this peer. Whether we unchoke it is up to the manager's choker.
         PacketHeader {
             len: 1,
             id: 2, // interested
         }
         .write(&mut bytes)?;
 
+        // Extended messages are dealt with here by the extensions themselves, rather than by the manager
+        let mut extension_registry = ExtensionRegistry::new(extensions);
+        if has_extension_bit(&handshake_reply.reserved) {
+            extension_registry.handshake(peer, &client_config)?.write(&mut bytes)?;
+        }
+
         stream.write_all(&bytes).await?;
 
-        let mut data_buf = buf[..buf_len][handshake_cursor.position() as usize..].to_vec();
+        let mut data_buf = Vec::new();
+        let mut extension_tick_interval = tokio::time::interval(EXTENSION_TICK_INTERVAL);
 
         loop {
             let packets = read_packets(&mut data_buf)?;
 
             for packet in packets {
-                manager_tx.send(PeerPacket { packet, peer }).await?;
+                match packet {
+                    Packet::Extended(extended_packet) => {
+                        let mut bytes = vec![];
+                        for response in extension_registry.handle(extended_packet)? {
+                            response.write(&mut bytes)?;
+                        }
+                        stream.write_all(&bytes).await?;
+                    },
+                    packet => manager_tx.send(PeerPacket { packet, peer }).await?,
+                }
             }
 
             tokio::select! {
                 _ = stream.readable() => {
                     let mut buf = [0u8; 4096];
                     let buf_len = match stream.try_read(&mut buf) {
+                        // The peer has closed the connection
+                        Ok(0) => return Ok(()),
                         Ok(ok) => ok,
                         Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
                             // False positive; turns out the stream wasn't readable.
@@ -261,8 +441,67 @@ async fn peer_thread(
                                 }.write(&mut bytes)?;
                                 stream.write_all(&bytes).await?;
                             }
+                            PeerOutgoingMessage::RejectRequest { index, begin, length } => {
+                                if fast_extension {
+                                    let mut bytes = vec![];
+                                    RequestPacket {
+                                        header: PacketHeader { len: 13, id: 16 },
+                                        index,
+                                        begin,
+                                        length
+                                    }.write(&mut bytes)?;
+                                    stream.write_all(&bytes).await?;
+                                }
+                            }
+                            PeerOutgoingMessage::Have { index } => {
+                                let mut bytes = vec![];
+                                HavePacket {
+                                    header: PacketHeader { len: 5, id: 4 },
+                                    index,
+                                }.write(&mut bytes)?;
+                                stream.write_all(&bytes).await?;
+                            }
+                            PeerOutgoingMessage::Choke { rejected } => {
+                                let mut bytes = vec![];
+                                PacketHeader { len: 1, id: 0 }.write(&mut bytes)?;
+                                if fast_extension {
+                                    for (index, begin, length) in rejected {
+                                        RequestPacket {
+                                            header: PacketHeader { len: 13, id: 16 },
+                                            index,
+                                            begin,
+                                            length
+                                        }.write(&mut bytes)?;
+                                    }
+                                }
+                                stream.write_all(&bytes).await?;
+                            }
+                            PeerOutgoingMessage::Unchoke => {
+                                let mut bytes = vec![];
+                                PacketHeader { len: 1, id: 1 }.write(&mut bytes)?;
+                                stream.write_all(&bytes).await?;
+                            }
                         }
                     }
+                },
+
+                Some(OutgoingBlock { index, begin, block }) = block_rx.recv() => {
+                    let mut bytes = vec![];
+                    PiecePacket {
+                        header: PacketHeader { len: 9 + block.len() as u32, id: 7 },
+                        index,
+                        begin,
+                        block,
+                    }.write(&mut bytes)?;
+                    stream.write_all(&bytes).await?;
+                },
+
+                _ = extension_tick_interval.tick() => {
+                    let mut bytes = vec![];
+                    for packet in extension_registry.tick()? {
+                        packet.write(&mut bytes)?;
+                    }
+                    stream.write_all(&bytes).await?;
                 }
             }
         }
@@ -281,10 +520,18 @@ async fn peer_thread_wrapper(thread: impl Future<Output = Result<()>>, peer: Soc
 #[derive(Clone)]
 struct PeerState {
     choking_us: bool,
-    interested_in_us: bool,
     bitfield: BoolVec,
     currently_downloading_piece: Option<usize>,
-    tx: mpsc::Sender<PeerOutgoingMessage>
+    // The number of pieces this peer has sent us that failed their hash check
+    hash_failures: usize,
+    // Whether the peer connected to us, in which case its address isn't one it can be reached on
+    incoming: bool,
+    am_choking: bool,
+    // Blocks the peer has asked us for, as (index, begin, length), in the order they were asked for
+    requests: VecDeque<(u32, u32, u32)>,
+    // Unbounded, so that the manager never waits on a peer thread, which may itself be waiting on the manager
+    tx: mpsc::UnboundedSender<PeerOutgoingMessage>,
+    block_tx: mpsc::Sender<OutgoingBlock>,
 }
 
 #[derive(Debug, Clone)]
@@ -295,9 +542,71 @@ enum PieceState {
     Finished,
 }
 
+// A file on disk, and the range of bytes within the torrent as a whole that it holds
+#[derive(Debug)]
+struct FileSpan {
+    handle: File,
+    start: usize,
+    length: usize,
+}
+
+// Writes data at the given offset into the torrent, splitting it across however many files it spans
+async fn write_spanning_files(file_spans: &mut [FileSpan], offset: usize, data: &[u8]) -> Result<()> {
+    let end = offset + data.len();
+
+    for f in file_spans.iter_mut() {
+        let file_end = f.start + f.length;
+        if file_end <= offset || f.start >= end {
+            continue;
+        }
+
+        let write_start = std::cmp::max(offset, f.start);
+        let write_end = std::cmp::min(end, file_end);
+
+        f.handle.seek(SeekFrom::Start((write_start - f.start) as u64)).await?;
+        f.handle.write_all(&data[write_start - offset..write_end - offset]).await?;
+    }
+
+    Ok(())
+}
+
+// The counterpart to write_spanning_files, reading length bytes from the given offset into the torrent
+async fn read_spanning_files(file_spans: &mut [FileSpan], offset: usize, length: usize) -> Result<Vec<u8>> {
+    let end = offset + length;
+    let mut data = vec![0u8; length];
+
+    for f in file_spans.iter_mut() {
+        let file_end = f.start + f.length;
+        if file_end <= offset || f.start >= end {
+            continue;
+        }
+
+        let read_start = std::cmp::max(offset, f.start);
+        let read_end = std::cmp::min(end, file_end);
+
+        f.handle.seek(SeekFrom::Start((read_start - f.start) as u64)).await?;
+        f.handle.read_exact(&mut data[read_start - offset..read_end - offset]).await?;
+    }
+
+    Ok(data)
+}
+
+fn piece_hash_matches(metainfo: &Metainfo, piece_index: usize, piece: &[u8]) -> bool {
+    let mut hasher = Sha1::new();
+    hasher.update(piece);
+    hasher.finalize()[..] == metainfo.pieces[piece_index]
+}
+
 async fn preallocate_file(path: &Path, length: usize) -> Result<File> {
     // TODO: This definitely isn't the most efficient way to preallocate large files
-    let mut f = tokio::fs::File::create(path).await?;
+    // Finished pieces are read back from the files to be sent on to other peers
+    let mut f = tokio::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .await?;
 
     // ~100MB buffer
     let buf = vec![0u8; 1 << 27];
@@ -314,12 +623,12 @@ async fn preallocate_file(path: &Path, length: usize) -> Result<File> {
 }
 
 fn flag_next_piece(metainfo: &Metainfo, peer_state: &PeerState, pieces_state: &mut [PieceState]) -> Option<usize> {
-    for piece_index in 0..metainfo.pieces.len() {
+    for (piece_index, piece_state) in pieces_state.iter_mut().enumerate().take(metainfo.pieces.len()) {
         if peer_state.bitfield.get(piece_index).unwrap_or(false) &&
-            matches!(pieces_state[piece_index], PieceState::Unstarted | PieceState::Stalled { block_index: _ })
+            matches!(piece_state, PieceState::Unstarted | PieceState::Stalled { block_index: _ })
         {
-            let block_index = if let PieceState::Stalled {block_index: b} = pieces_state[piece_index] {b} else {0};
-            pieces_state[piece_index] = PieceState::Downloading { block_index };
+            let block_index = if let PieceState::Stalled {block_index: b} = piece_state {*b} else {0};
+            *piece_state = PieceState::Downloading { block_index };
             return Some(piece_index);
         }
     };
@@ -327,34 +636,28 @@ fn flag_next_piece(metainfo: &Metainfo, peer_state: &PeerState, pieces_state: &m
     None
 }
 
-async fn request_next_block(piece_index: usize, metainfo: &Metainfo, peer_state: &PeerState, pieces_state: &mut [PieceState]) -> Result<()> {
+fn request_next_block(piece_index: usize, metainfo: &Metainfo, peer_state: &mut PeerState, pieces_state: &mut [PieceState]) {
     if let PieceState::Downloading { block_index } = pieces_state[piece_index] {
-        let piece_len = if piece_index == metainfo.pieces.len() - 1 {
-            metainfo.total_length % metainfo.piece_length as usize
-        } else {
-            metainfo.piece_length as usize
-        };
+        let piece_len = metainfo.length_of_piece(piece_index);
 
-        let num_blocks = (piece_len - 1) / (BLOCK_LENGTH as usize) + 1;
-        let block_length = if piece_len % BLOCK_LENGTH as usize == 0 {
-            BLOCK_LENGTH
-        } else if block_index + 1 == num_blocks {
-            piece_len as u32 % BLOCK_LENGTH
-        } else {
-            BLOCK_LENGTH
-        };
+        // Every block but the last is BLOCK_LENGTH long
+        let block_length = std::cmp::min(BLOCK_LENGTH as usize, piece_len - block_index * BLOCK_LENGTH as usize) as u32;
 
-        peer_state.tx.send(PeerOutgoingMessage::RequestBlock {
+        let sent = peer_state.tx.send(PeerOutgoingMessage::RequestBlock {
             index: piece_index as u32,
             begin: block_index as u32 * BLOCK_LENGTH,
             length: block_length
-        }).await?;
+        });
 
-        pieces_state[piece_index] = PieceState::Downloading { block_index: block_index + 1 };
-        
-        Ok(())
+        if sent.is_ok() {
+            pieces_state[piece_index] = PieceState::Downloading { block_index: block_index + 1 };
+        } else {
+            // The peer thread has exited, so someone else will have to pick the piece up from here
+            pieces_state[piece_index] = PieceState::Stalled { block_index };
+            peer_state.currently_downloading_piece = None;
+        }
     } else {
-        Err(anyhow!("request_next_block called on piece with a PieceState other than Downloading"))
+        eprintln!("WARNING: request_next_block called on piece with a PieceState other than Downloading");
     }
 }
 
@@ -362,26 +665,104 @@ pub struct Downloader {
     metainfo: Metainfo,
     peers: PeerList,
     client_config: ClientConfig,
+    stats: Arc<TransferStats>,
+    // The peers we're currently connected to, along with their PEX flags
+    connected_peers: watch::Sender<HashMap<SocketAddr, u8>>,
+    // New peers, from trackers or from other peers, are sent down here to be added to the pool
+    discovered_tx: mpsc::UnboundedSender<SocketAddr>,
+    discovered_rx: mpsc::UnboundedReceiver<SocketAddr>,
+    // Peers that have connected to us, as handed over by the listener
+    incoming_tx: mpsc::Sender<IncomingPeer>,
+    incoming_rx: mpsc::Receiver<IncomingPeer>,
 }
 
 impl Downloader {
-    pub fn new(metainfo: Metainfo, peers: PeerList, client_config: ClientConfig) -> Self {
+    pub fn new(metainfo: Metainfo, peers: PeerList, stats: Arc<TransferStats>, client_config: ClientConfig) -> Self {
+        stats.left.store(metainfo.total_length as u64, Ordering::Relaxed);
+        let (discovered_tx, discovered_rx) = mpsc::unbounded_channel();
+        let (incoming_tx, incoming_rx) = mpsc::channel(8);
+
         Self {
             metainfo,
             peers,
             client_config,
+            stats,
+            connected_peers: watch::channel(HashMap::new()).0,
+            discovered_tx,
+            discovered_rx,
+            incoming_tx,
+            incoming_rx,
         }
     }
 
-    pub async fn download(mut self) -> Result<()> {
-        // First, preallocate space for all our files
-        #[derive(Debug)]
-        struct FileSpan {
-            handle: File,
-            start: usize,
-            length: usize,
+    // For handing over peers that connect to us
+    pub fn incoming_sender(&self) -> mpsc::Sender<IncomingPeer> {
+        self.incoming_tx.clone()
+    }
+
+    // For adding peers to the pool while the download's running
+    pub fn peer_sender(&self) -> mpsc::UnboundedSender<SocketAddr> {
+        self.discovered_tx.clone()
+    }
+
+    // The extension protocol extensions to offer each peer we connect to.
+    // Peers found through any of them are sent down discovered_peers.
+    fn extensions_for_peer(&self, peer: SocketAddr, discovered_peers: &mpsc::UnboundedSender<SocketAddr>) -> Vec<Box<dyn Extension>> {
+        // Private torrents must only get their peers from their trackers (BEP 27)
+        if self.metainfo.private {
+            return Vec::new();
         }
 
+        vec![Box::new(PeerExchange::new(peer, self.connected_peers.subscribe(), discovered_peers.clone()))]
+    }
+
+    // Starts a peer thread for the given connection, returning it along with the peer's initial state
+    fn spawn_peer_thread(
+        &self,
+        peer: SocketAddr,
+        connection: PeerConnection,
+        manager_tx: &mpsc::Sender<PeerPacket>,
+        pieces_state: &[PieceState],
+    ) -> (JoinHandle<PeerThreadResult>, PeerState) {
+        let (thread_tx, thread_rx) = mpsc::unbounded_channel();
+        let (block_tx, block_rx) = mpsc::channel(32);
+        let incoming = matches!(connection, PeerConnection::Incoming(..));
+
+        let handle = tokio::spawn(peer_thread_wrapper(
+            peer_thread(
+                peer,
+                connection,
+                self.client_config.clone(),
+                self.metainfo.clone(),
+                ManagerChannels {
+                    tx: manager_tx.clone(),
+                    rx: thread_rx,
+                    blocks: block_rx,
+                },
+                self.extensions_for_peer(peer, &self.discovered_tx),
+                pieces_state.iter().map(|piece_state| matches!(piece_state, PieceState::Finished)).collect(),
+            ),
+            peer
+        ));
+
+        let peer_state = PeerState {
+            choking_us: true,
+            bitfield: BoolVec::filled_with(self.metainfo.pieces.len(), false),
+            currently_downloading_piece: None,
+            hash_failures: 0,
+            incoming,
+            // Peers stay choked until the choker picks them
+            am_choking: true,
+            requests: VecDeque::new(),
+            tx: thread_tx,
+            block_tx,
+        };
+
+        (handle, peer_state)
+    }
+
+    pub async fn download(mut self) -> Result<()> {
+        // First, preallocate space for all our files
         let mut file_handles = Vec::new();
 
         match self.metainfo.info {
@@ -400,6 +781,9 @@ impl Downloader {
                 let mut start = 0;
                 for file in &files_info.files {
                     let file_path = self.client_config.download_dir.join(&files_info.name).join(file.path.iter().collect::<PathBuf>());
+                    if let Some(parent) = file_path.parent() {
+                        tokio::fs::create_dir_all(parent).await?;
+                    }
                     let f = preallocate_file(&file_path, file.length as usize).await?;
                     file_handles.push(FileSpan {
                         handle: f,
@@ -421,6 +805,12 @@ impl Downloader {
         let mut pieces_state = Vec::new();
         pieces_state.resize(self.metainfo.pieces.len(), PieceState::Unstarted);
 
+        // Blocks are held in memory until their whole piece has arrived and its hash has been checked
+        let mut piece_buffers: HashMap<usize, Vec<u8>> = HashMap::new();
+
+        let mut choker = Choker::new(self.client_config.upload_slots);
+        let mut rechoke_interval = tokio::time::interval(RECHOKE_INTERVAL);
+
         loop {
             tokio::select! {
                 _ = peer_update_interval.tick() => {
@@ -430,41 +820,56 @@ impl Downloader {
                             .into_iter()
                             .find(|p| !peer_states.contains_key(p)) {
                                 Some(p) => p,
-                                None => break,
+                                None => {
+                                    // Have the trackers send us more peers as soon as they'll let us
+                                    self.stats.peers_wanted.notify_waiters();
+                                    break;
+                                },
                             };
 
                         println!("Spawning peer thread {}", peer);
 
-                        let (thread_tx, thread_rx) = mpsc::channel(32);
-                            
-                        let handle = tokio::spawn(peer_thread_wrapper(
-                            peer_thread(
-                                peer,
-                                self.client_config.clone(),
-                                self.metainfo.clone(),
-                                tx.clone(),
-                                thread_rx,
-                            ),
-                            peer
-                        ));
-            
+                        let (handle, peer_state) = self.spawn_peer_thread(peer, PeerConnection::Outgoing, &tx, &pieces_state);
                         peer_thread_futures.push(handle);
-                        peer_states.insert(peer, PeerState {
-                            choking_us: true,
-                            interested_in_us: false,
-                            bitfield: BoolVec::filled_with(self.metainfo.pieces.len(), false),
-                            currently_downloading_piece: None,
-                            tx: thread_tx,
-                        });
+                        peer_states.insert(peer, peer_state);
+                        choker.add_peer(peer, Instant::now());
                     }
                 },
 
+                Some(IncomingPeer { peer, stream, handshake }) = self.incoming_rx.recv() => {
+                    // Incoming connections count towards the same limit as the ones we make
+                    if peer_thread_futures.len() >= self.client_config.active_peers || peer_states.contains_key(&peer) {
+                        continue;
+                    }
+
+                    println!("Accepted connection from {}", peer);
+
+                    let (handle, peer_state) = self.spawn_peer_thread(peer, PeerConnection::Incoming(stream, handshake), &tx, &pieces_state);
+                    peer_thread_futures.push(handle);
+                    peer_states.insert(peer, peer_state);
+                    choker.add_peer(peer, Instant::now());
+                },
+
                 peer_packet = rx.recv() => {
                     if let Some(peer_packet) = peer_packet {
                         let packet = peer_packet.packet;
                         let peer = peer_packet.peer;
                         let peer_state = peer_states.get_mut(&peer).unwrap();
 
+                        // Hearing from a peer means we've completed our handshake with it.
+                        // Peers that connected to us aren't passed on to others, as we don't know which port they listen on.
+                        if !peer_state.incoming {
+                            self.connected_peers.send_if_modified(|connected_peers| match connected_peers.entry(peer) {
+                                Entry::Occupied(_) => false,
+                                Entry::Vacant(entry) => {
+                                    entry.insert(PEX_FLAG_REACHABLE);
+                                    true
+                                }
+                            });
+                        }
+
+                        let mut finished_piece = None;
+
                         match packet {
                             Packet::KeepAlive => eprintln!("WARNING: Ignoring keep-alive received from {}", peer),
                             Packet::Choke => peer_state.choking_us = true,
@@ -476,21 +881,42 @@ impl Downloader {
                                     // find the first unstarted / stalled piece we need that this peer has
                                     if let Some(piece_index) = flag_next_piece(&self.metainfo, peer_state, &mut pieces_state) {
                                         peer_state.currently_downloading_piece = Some(piece_index);
-                                        request_next_block(piece_index, &self.metainfo, peer_state, &mut pieces_state).await?;
+                                        request_next_block(piece_index, &self.metainfo, peer_state, &mut pieces_state);
                                     } else {
                                         eprintln!("WARNING: No pieces available to download from peer.");
                                     }
                                 }
                             },
-                            Packet::Interested => peer_state.interested_in_us = true,
-                            Packet::NotInterested => peer_state.choking_us = false,
+                            Packet::Interested => choker.set_interested(&peer, true),
+                            Packet::NotInterested => choker.set_interested(&peer, false),
                             Packet::Have(have_packet) => {
                                 peer_state.bitfield.set(have_packet.index as usize, true);
                             },
                             Packet::Bitfield(bitfield_packet) => {
                                 peer_state.bitfield = BoolVec::from_vec(bitfield_packet.bitfield);
+
+                                if !peer_state.incoming && (0..self.metainfo.pieces.len()).all(|i| peer_state.bitfield.get(i).unwrap_or(false)) {
+                                    self.connected_peers.send_modify(|connected_peers| {
+                                        connected_peers.insert(peer, PEX_FLAG_REACHABLE | PEX_FLAG_SEED);
+                                    });
+                                }
+                            },
+                            Packet::Request(RequestPacket { index, begin, length, .. }) => {
+                                // Requests from peers we're choking are dropped, as are any for data we don't have
+                                let valid = !peer_state.am_choking
+                                    && matches!(pieces_state.get(index as usize), Some(PieceState::Finished))
+                                    && length <= MAX_REQUEST_LENGTH
+                                    && begin as usize + length as usize <= self.metainfo.length_of_piece(index as usize)
+                                    && peer_state.requests.len() < MAX_QUEUED_REQUESTS as usize;
+
+                                if valid {
+                                    peer_state.requests.push_back((index, begin, length));
+                                } else {
+                                    eprintln!("WARNING: Rejecting invalid request for piece {} from {}", index, peer);
+                                    // The peer may have disconnected since sending the request
+                                    let _ = peer_state.tx.send(PeerOutgoingMessage::RejectRequest { index, begin, length });
+                                }
                             },
-                            Packet::Request(_) => eprintln!("WARNING: Ignoring request for a piece from {}", peer),
                             Packet::Piece(piece_packet) => {
                                 let piece_index = piece_packet.index as usize;
                                 if let PieceState::Downloading { block_index } = pieces_state[piece_index] {
@@ -499,83 +925,197 @@ impl Downloader {
                                     // As such, the block index we just received is the block index saved less one.
                                     let block_index = block_index - 1;
                                     
-                                    // Write this piece out to disk
-                                    // First, what file is this piece from?
-                                    let block_torrent_offset = piece_index * (self.metainfo.piece_length as usize) + block_index * BLOCK_LENGTH as usize;
+                                    let piece_len = self.metainfo.length_of_piece(piece_index);
+                                    let block_start = piece_packet.begin as usize;
+                                    let block_end = block_start + piece_packet.block.len();
+
+                                    if block_end > piece_len {
+                                        eprintln!("WARNING: {} sent a block past the end of piece {}; ignoring it.", peer, piece_index);
+                                        pieces_state[piece_index] = PieceState::Stalled { block_index };
+                                        peer_state.currently_downloading_piece = None;
+                                        continue;
+                                    }
 
-                                    let file_index = file_handles.iter_mut()
-                                        .position(|f| (f.start + f.length) > block_torrent_offset)
-                                        .ok_or_else(|| anyhow!("Piece index out of range for files provided (?)"))?;
+                                    let piece_buffer = piece_buffers.entry(piece_index).or_insert_with(|| vec![0; piece_len]);
+                                    piece_buffer[block_start..block_end].copy_from_slice(&piece_packet.block);
+                                    self.stats.downloaded.fetch_add(piece_packet.block.len() as u64, Ordering::Relaxed);
+                                    choker.record_downloaded(&peer, piece_packet.block.len() as u64, Instant::now());
 
-                                    let f = &mut file_handles[file_index];
-                                    let write_length = std::cmp::min(f.start + f.length - block_torrent_offset, piece_packet.block.len());
+                                    let num_blocks = (piece_len - 1) / (BLOCK_LENGTH as usize) + 1;
 
-                                    f.handle.seek(SeekFrom::Start((block_torrent_offset - f.start) as u64)).await?;
-                                    f.handle.write_all(&piece_packet.block[..write_length]).await?;
+                                    if block_index + 1 >= num_blocks {
+                                        // Unwrap is safe here, as the buffer was inserted above
+                                        let piece_buffer = piece_buffers.remove(&piece_index).unwrap();
 
-                                    if write_length < piece_packet.block.len() as usize && file_index + 1 < file_handles.len() {
-                                        // This block stretches past the end of this file, and into the next
-                                        let next_file = &mut file_handles[file_index + 1];
-                                        next_file.handle.seek(SeekFrom::Start(0)).await?;
-                                        next_file.handle.write_all(&piece_packet.block[write_length..]).await?;
-                                    }
+                                        if piece_hash_matches(&self.metainfo, piece_index, &piece_buffer) {
+                                            write_spanning_files(&mut file_handles, piece_index * self.metainfo.piece_length as usize, &piece_buffer).await?;
+                                            pieces_state[piece_index] = PieceState::Finished;
+                                            self.stats.left.fetch_sub(piece_len as u64, Ordering::Relaxed);
 
-                                    // Is this piece finished?
-                                    let piece_len = if piece_index == self.metainfo.pieces.len() - 1 {
-                                        self.metainfo.total_length % self.metainfo.piece_length as usize
-                                    } else {
-                                        self.metainfo.piece_length as usize
-                                    };
+                                            let finished_pieces = pieces_state.iter()
+                                                    .filter(|p| matches!(p, PieceState::Finished))
+                                                    .count();
 
-                                    let num_blocks = (piece_len - 1) / (BLOCK_LENGTH as usize) + 1;
-                                    
-                                    if block_index + 1 >= num_blocks {
-                                        pieces_state[piece_index] = PieceState::Finished;
-                                        
-                                        let finished_pieces = pieces_state.iter()
-                                                .filter(|p| matches!(p, PieceState::Finished))
-                                                .count();
+                                            println!("Finished downloading piece {}, {}% complete.", piece_index, (finished_pieces as f32 / pieces_state.len() as f32) * 100.);
 
-                                        println!("Finished downloading piece {}, {}% complete.", piece_index, (finished_pieces as f32 / pieces_state.len() as f32) * 100.);
+                                            finished_piece = Some(piece_index);
+
+                                            if finished_pieces == pieces_state.len() {
+                                                println!("Download complete; seeding until interrupted.");
+                                                self.stats.complete.send_replace(true);
+                                            }
+                                        } else {
+                                            // Throw the piece away and start it again from scratch, possibly from another peer
+                                            pieces_state[piece_index] = PieceState::Unstarted;
+                                            peer_state.hash_failures += 1;
+
+                                            eprintln!(
+                                                "WARNING: Piece {} from {} failed its hash check; re-queueing it. {} failed pieces from this peer so far.",
+                                                piece_index, peer, peer_state.hash_failures
+                                            );
+                                        }
 
                                         if let Some(next_piece_index) = flag_next_piece(&self.metainfo, peer_state, &mut pieces_state) {
-                                            peer_state.currently_downloading_piece = Some(piece_index);
-                                            request_next_block(next_piece_index, &self.metainfo, peer_state, &mut pieces_state).await?
+                                            peer_state.currently_downloading_piece = Some(next_piece_index);
+                                            request_next_block(next_piece_index, &self.metainfo, peer_state, &mut pieces_state)
                                         } else {
+                                            peer_state.currently_downloading_piece = None;
                                             eprintln!("WARNING: No more pieces available to download from peer.");
                                         }
                                     } else {
-                                        request_next_block(piece_index, &self.metainfo, peer_state, &mut pieces_state).await?;
+                                        request_next_block(piece_index, &self.metainfo, peer_state, &mut pieces_state);
                                     };
 
                                  } else {
                                     eprintln!("WARNING: received piece data for a block not currently being downloaded.");
                                 }
                             },
-                            Packet::Cancel(_) => eprintln!("WARNING: cancel packet from {} ignored", peer),
+                            Packet::Cancel(CancelPacket { index, begin, length, .. }) => {
+                                // The fast extension has every cancelled request we've yet to answer answered with a reject
+                                let queued = peer_state.requests.len();
+                                peer_state.requests.retain(|request| *request != (index, begin, length));
+                                if peer_state.requests.len() < queued {
+                                    let _ = peer_state.tx.send(PeerOutgoingMessage::RejectRequest { index, begin, length });
+                                }
+                            },
+                            Packet::HaveAll => {
+                                peer_state.bitfield = BoolVec::filled_with(self.metainfo.pieces.len(), true);
+
+                                if !peer_state.incoming {
+                                    self.connected_peers.send_modify(|connected_peers| {
+                                        connected_peers.insert(peer, PEX_FLAG_REACHABLE | PEX_FLAG_SEED);
+                                    });
+                                }
+                            },
+                            Packet::HaveNone => peer_state.bitfield = BoolVec::filled_with(self.metainfo.pieces.len(), false),
+                            Packet::Reject(RequestPacket { index, begin, .. }) => {
+                                // Someone else can pick the piece up from the rejected block
+                                let piece_index = index as usize;
+                                if peer_state.currently_downloading_piece == Some(piece_index) {
+                                    if let Some(PieceState::Downloading { .. }) = pieces_state.get(piece_index) {
+                                        pieces_state[piece_index] = PieceState::Stalled { block_index: (begin / BLOCK_LENGTH) as usize };
+                                    }
+                                    peer_state.currently_downloading_piece = None;
+                                }
+                            },
+                            // We only ever ask for the next piece we need, so suggestions are no help to us,
+                            // and we're only choked by peers we're still waiting on for an unchoke anyway
+                            Packet::Suggest | Packet::AllowedFast => {},
+                            // These are handled by the extensions in each peer thread, and never reach us here
+                            Packet::Extended(_) => {},
                         };
+
+                        // Let every peer know we've got the piece, so they can ask us for it
+                        if let Some(piece_index) = finished_piece {
+                            for peer_state in peer_states.values() {
+                                // This only fails for peers whose threads have exited. Those still connecting
+                                // find the message waiting for them once they start reading.
+                                let _ = peer_state.tx.send(PeerOutgoingMessage::Have { index: piece_index as u32 });
+                            }
+                        }
                     };
                 },
 
-                peer_fut = peer_thread_futures.next() => {
+                // Requests are served a block at a time, one from each peer in turn, so that the other branches get a look in.
+                // That gives cancels the chance to arrive before the blocks they're for are sent.
+                // Peers whose threads have yet to take the blocks they've already been given wait for them to catch up,
+                // as waiting on a slow peer here would hold up every other.
+                _ = std::future::ready(()), if peer_states.values().any(|peer_state| !peer_state.requests.is_empty() && peer_state.block_tx.capacity() > 0) => {
+                    for (peer, peer_state) in peer_states.iter_mut() {
+                        if peer_state.requests.is_empty() {
+                            continue;
+                        }
+
+                        let permit = match peer_state.block_tx.try_reserve() {
+                            Ok(permit) => permit,
+                            Err(TrySendError::Full(())) => continue,
+                            // The peer thread has exited, so there's no one to send to
+                            Err(TrySendError::Closed(())) => {
+                                eprintln!("WARNING: Unable to send blocks to {}", peer);
+                                peer_state.requests.clear();
+                                continue;
+                            },
+                        };
+
+                        // Unwrap is safe here, as the queue was checked above
+                        let (index, begin, length) = peer_state.requests.pop_front().unwrap();
+                        let offset = index as usize * self.metainfo.piece_length as usize + begin as usize;
+                        let block = read_spanning_files(&mut file_handles, offset, length as usize).await?;
+
+                        permit.send(OutgoingBlock { index, begin, block });
+                        self.stats.uploaded.fetch_add(length as u64, Ordering::Relaxed);
+                        choker.record_uploaded(peer, length as u64);
+                    }
+                },
+
+                _ = rechoke_interval.tick() => {
+                    let seeding = pieces_state.iter().all(|piece_state| matches!(piece_state, PieceState::Finished));
+
+                    for (peer, choked) in choker.rechoke(Instant::now(), seeding) {
+                        let peer_state = peer_states.get_mut(&peer).unwrap();
+                        peer_state.am_choking = choked;
+
+                        // Peers whose threads have exited can't be told anything
+                        if choked {
+                            let rejected = peer_state.requests.drain(..).collect();
+                            let _ = peer_state.tx.send(PeerOutgoingMessage::Choke { rejected });
+                        } else {
+                            let _ = peer_state.tx.send(PeerOutgoingMessage::Unchoke);
+                        }
+                    }
+                },
+
+                Some(peer) = self.discovered_rx.recv() => {
+                    self.peers.0.insert(peer);
+                },
+
+                // With no peer threads running, this would be ready immediately, and the loop would never yield
+                peer_fut = peer_thread_futures.next(), if !peer_thread_futures.is_empty() => {
                     if let Some(res) = peer_fut {
                             match res {
                                 Ok(res) => {
                                     let PeerThreadResult {result, peer} = res;
+
+                                    self.connected_peers.send_if_modified(|connected_peers| connected_peers.remove(&peer).is_some());
+                                    choker.remove_peer(&peer);
+
                                     match result {
                                         Ok(_) => println!("Peer thread {} exited gracefully", peer),
                                         Err(e) => {
                                             eprintln!("Peer thread {} exited with error {}; removing from pool.", peer, e);
                                             self.peers.0.remove(&peer);
+                                        },
+                                    };
 
-                                            // Mark the piece we were downloading from this peer as stalled
-                                            // so another peer can pick up the work from it
-                                            if let Some(piece_index) = peer_states.get(&peer).unwrap().currently_downloading_piece {
-                                                if let PieceState::Downloading { block_index } = pieces_state[piece_index] {
-                                                    pieces_state[piece_index] = PieceState::Stalled { block_index };
-                                                };
+                                    // Mark the piece we were downloading from this peer as stalled
+                                    // so another peer can pick up the work from it, starting with the block
+                                    // we'd asked for and never got
+                                    if let Some(peer_state) = peer_states.get_mut(&peer) {
+                                        if let Some(piece_index) = peer_state.currently_downloading_piece.take() {
+                                            if let PieceState::Downloading { block_index } = pieces_state[piece_index] {
+                                                pieces_state[piece_index] = PieceState::Stalled { block_index: block_index.saturating_sub(1) };
                                             };
-                                        },
+                                        };
                                     };
                                 },
                                 Err(e) => eprintln!("Error joining peer thread: {}", e),
@@ -0,0 +1,238 @@
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::{bencode, downloader::ExtendedPacket, ClientConfig};
+
+// The extended message ID reserved for the extended handshake itself
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+// The number of outstanding requests we'll queue up from a single peer
+pub const MAX_QUEUED_REQUESTS: u32 = 250;
+// How often extensions get the chance to send messages of their own accord
+pub const EXTENSION_TICK_INTERVAL: Duration = Duration::from_secs(5);
+
+// Support for the extension protocol (BEP 10) is flagged by bit 20 from the right of the reserved bytes
+pub fn set_extension_bit(reserved: &mut [u8; 8]) {
+    reserved[5] |= 0x10;
+}
+
+pub fn has_extension_bit(reserved: &[u8; 8]) -> bool {
+    reserved[5] & 0x10 != 0
+}
+
+// Everything in the extended handshake but m is optional, so a field that doesn't parse as expected
+// is treated as missing, rather than failing the whole handshake
+fn lenient<'de, D: Deserializer<'de>, T: Deserialize<'de>>(deserializer: D) -> Result<Option<T>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Lenient<T> {
+        Valid(T),
+        Invalid(serde::de::IgnoredAny),
+    }
+
+    Ok(match Lenient::deserialize(deserializer)? {
+        Lenient::Valid(value) => Some(value),
+        Lenient::Invalid(_) => None,
+    })
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct ExtendedHandshake {
+    // Maps the name of each supported extension to the ID it's to be sent with. An ID of 0 disables the extension.
+    #[serde(default)]
+    pub m: HashMap<String, i64>,
+    // Client name and version, which needn't be UTF-8
+    #[serde(default, deserialize_with = "lenient")]
+    pub v: Option<ByteBuf>,
+    // The sender's listening port
+    #[serde(default, deserialize_with = "lenient")]
+    pub p: Option<u16>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub reqq: Option<u32>,
+    // The receiver's IP address as seen by the sender, as 4 or 16 bytes
+    #[serde(default, deserialize_with = "lenient")]
+    pub yourip: Option<ByteBuf>,
+    // The size of the info dict in bytes, for ut_metadata
+    #[serde(default, deserialize_with = "lenient")]
+    pub metadata_size: Option<usize>,
+}
+
+// An extension to the peer wire protocol negotiated through the extended handshake, such as ut_metadata.
+// Each extension only ever sees the payloads of its own messages, and returns the payloads of any messages
+// it wants sent to the peer in response.
+pub trait Extension: Send {
+    // The name the extension is known by in the m dictionary
+    fn name(&self) -> &'static str;
+
+    // Adds any extension-specific keys to our extended handshake
+    fn extend_handshake(&self, _handshake: &mut ExtendedHandshake) {}
+
+    // Called once the peer's extended handshake arrives, if the peer supports this extension
+    fn on_handshake(&mut self, _handshake: &ExtendedHandshake) -> Result<Vec<Vec<u8>>> {
+        Ok(Vec::new())
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>>;
+
+    // Called every EXTENSION_TICK_INTERVAL, for extensions that send messages of their own accord
+    fn on_tick(&mut self) -> Result<Vec<Vec<u8>>> {
+        Ok(Vec::new())
+    }
+}
+
+// The extensions in use on a single connection, and the message IDs each side has assigned them.
+// We assign our IDs in order of registration, starting from 1.
+pub struct ExtensionRegistry {
+    extensions: Vec<Box<dyn Extension>>,
+    // The IDs the peer wants each extension's messages sent with, if it supports them
+    their_ids: Vec<Option<u8>>,
+    their_handshake: Option<ExtendedHandshake>,
+}
+
+impl ExtensionRegistry {
+    pub fn new(extensions: Vec<Box<dyn Extension>>) -> Self {
+        let their_ids = vec![None; extensions.len()];
+
+        Self {
+            extensions,
+            their_ids,
+            their_handshake: None,
+        }
+    }
+
+    // Builds our extended handshake for the given peer
+    pub fn handshake(&self, peer: SocketAddr, client_config: &ClientConfig) -> Result<ExtendedPacket> {
+        let mut handshake = ExtendedHandshake {
+            m: self
+                .extensions
+                .iter()
+                .enumerate()
+                .map(|(i, extension)| (extension.name().to_string(), i as i64 + 1))
+                .collect(),
+            v: Some(ByteBuf::from(format!("downpour {}", env!("CARGO_PKG_VERSION")))),
+            p: Some(client_config.port),
+            reqq: Some(MAX_QUEUED_REQUESTS),
+            yourip: Some(ByteBuf::from(match peer {
+                SocketAddr::V4(addr) => addr.ip().octets().to_vec(),
+                SocketAddr::V6(addr) => addr.ip().octets().to_vec(),
+            })),
+            metadata_size: None,
+        };
+
+        for extension in &self.extensions {
+            extension.extend_handshake(&mut handshake);
+        }
+
+        Ok(ExtendedPacket::new(EXTENDED_HANDSHAKE_ID, bencode::to_bytes(&handshake)?))
+    }
+
+    // The peer's extended handshake, once it's been received
+    pub fn their_handshake(&self) -> Option<&ExtendedHandshake> {
+        self.their_handshake.as_ref()
+    }
+
+    pub fn peer_supports(&self, name: &str) -> bool {
+        self.extensions
+            .iter()
+            .zip(&self.their_ids)
+            .any(|(extension, id)| extension.name() == name && id.is_some())
+    }
+
+    // Wraps the payloads returned by an extension in messages addressed with the peer's ID for it
+    fn to_packets(&self, index: usize, payloads: Vec<Vec<u8>>) -> Vec<ExtendedPacket> {
+        match self.their_ids[index] {
+            Some(id) => payloads.into_iter().map(|payload| ExtendedPacket::new(id, payload)).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // Hands an extended message from the peer to the extension it's meant for,
+    // returning any messages to be sent back to the peer
+    pub fn handle(&mut self, packet: ExtendedPacket) -> Result<Vec<ExtendedPacket>> {
+        if packet.extended_id == EXTENDED_HANDSHAKE_ID {
+            let their_handshake: ExtendedHandshake = bencode::from_bytes(&packet.payload)
+                .map_err(|e| anyhow!("Invalid extended handshake: {}", e))?;
+
+            // The peer may send further handshakes to update its IDs, which replace all previous ones
+            for (extension, id) in self.extensions.iter().zip(self.their_ids.iter_mut()) {
+                *id = match their_handshake.m.get(extension.name()) {
+                    Some(&id) if (1..=u8::MAX as i64).contains(&id) => Some(id as u8),
+                    _ => None,
+                };
+            }
+
+            let mut packets = Vec::new();
+            for i in 0..self.extensions.len() {
+                if self.their_ids[i].is_some() {
+                    let payloads = self.extensions[i].on_handshake(&their_handshake)?;
+                    packets.extend(self.to_packets(i, payloads));
+                }
+            }
+
+            self.their_handshake = Some(their_handshake);
+            Ok(packets)
+        } else {
+            let index = packet.extended_id as usize - 1;
+            let extension = self
+                .extensions
+                .get_mut(index)
+                .ok_or_else(|| anyhow!("Extended message with unknown ID {}", packet.extended_id))?;
+
+            let payloads = extension.on_message(&packet.payload)?;
+            Ok(self.to_packets(index, payloads))
+        }
+    }
+
+    // Gives every extension the peer supports a chance to send messages, returning any it wants sent
+    pub fn tick(&mut self) -> Result<Vec<ExtendedPacket>> {
+        let mut packets = Vec::new();
+        for i in 0..self.extensions.len() {
+            if self.their_ids[i].is_some() {
+                let payloads = self.extensions[i].on_tick()?;
+                packets.extend(self.to_packets(i, payloads));
+            }
+        }
+
+        Ok(packets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn their_handshake(payload: &[u8]) -> Result<ExtendedHandshake> {
+        let mut registry = ExtensionRegistry::new(Vec::new());
+        registry.handle(ExtendedPacket::new(EXTENDED_HANDSHAKE_ID, payload.to_vec()))?;
+        Ok(registry.their_handshake().unwrap().clone())
+    }
+
+    #[test]
+    fn accepts_a_handshake_with_a_bad_client_version() {
+        let handshake = their_handshake(b"d1:md11:ut_metadatai3ee1:pi6881e1:vi12ee").unwrap();
+
+        assert_eq!(handshake.m.get("ut_metadata"), Some(&3));
+        assert_eq!(handshake.p, Some(6881));
+        assert_eq!(handshake.v, None);
+    }
+
+    #[test]
+    fn accepts_a_client_version_that_isnt_utf8() {
+        let handshake = their_handshake(b"d1:mde1:v3:\xff\xfe\xfde").unwrap();
+
+        assert_eq!(handshake.v, Some(ByteBuf::from(b"\xff\xfe\xfd".to_vec())));
+    }
+
+    #[test]
+    fn ignores_optional_fields_out_of_range() {
+        let handshake = their_handshake(b"d1:mde13:metadata_sizei-1e1:pi70000e4:reqqi-5e6:yourip4:\x7f\0\0\x01e").unwrap();
+
+        assert_eq!(handshake.metadata_size, None);
+        assert_eq!(handshake.p, None);
+        assert_eq!(handshake.reqq, None);
+        // The fields that do parse are kept
+        assert_eq!(handshake.yourip, Some(ByteBuf::from(vec![127, 0, 0, 1])));
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use data_encoding::{BASE32, HEXLOWER};
+use serde::Serialize;
+
+use crate::metainfo::{Info, Metainfo};
+
+#[derive(Serialize)]
+struct InspectOutput<'a> {
+    name: &'a str,
+    info_hash: InfoHashOutput,
+    multi_file: bool,
+    private: bool,
+    comment: Option<&'a str>,
+    created_by: Option<&'a str>,
+    creation_date: Option<i64>,
+    piece_length: u64,
+    piece_count: usize,
+    total_length: usize,
+    trackers: Vec<Vec<&'a str>>,
+    nodes: &'a [String],
+    files: Vec<FileOutput<'a>>,
+}
+
+#[derive(Serialize)]
+struct InfoHashOutput {
+    hex: String,
+    base32: String,
+}
+
+#[derive(Serialize)]
+struct FileOutput<'a> {
+    // Relative to the torrent's root directory; just the torrent's name for single-file torrents
+    path: Vec<&'a str>,
+    length: u64,
+}
+
+impl<'a> InspectOutput<'a> {
+    fn new(metainfo: &'a Metainfo) -> Self {
+        let (name, files) = match &metainfo.info {
+            Info::SingleFile(file_info) => (
+                file_info.name.as_str(),
+                vec![FileOutput {
+                    path: vec![file_info.name.as_str()],
+                    length: file_info.length,
+                }],
+            ),
+            Info::Directory(files_info) => (
+                files_info.name.as_str(),
+                files_info
+                    .files
+                    .iter()
+                    .map(|file| FileOutput {
+                        path: file.path.iter().map(String::as_str).collect(),
+                        length: file.length,
+                    })
+                    .collect(),
+            ),
+        };
+
+        Self {
+            name,
+            multi_file: matches!(metainfo.info, Info::Directory(_)),
+            info_hash: InfoHashOutput {
+                hex: HEXLOWER.encode(&metainfo.info_hash),
+                base32: BASE32.encode(&metainfo.info_hash),
+            },
+            private: metainfo.private,
+            comment: metainfo.comment.as_deref(),
+            created_by: metainfo.created_by.as_deref(),
+            creation_date: metainfo.creation_date,
+            piece_length: metainfo.piece_length,
+            piece_count: metainfo.pieces.len(),
+            total_length: metainfo.total_length,
+            trackers: metainfo
+                .announce_list
+                .iter()
+                .map(|tier| tier.iter().map(|url| url.as_str()).collect())
+                .collect(),
+            nodes: &metainfo.nodes,
+            files,
+        }
+    }
+}
+
+// A directory in the file tree printed for a torrent
+#[derive(Default)]
+struct Directory<'a> {
+    directories: BTreeMap<&'a str, Directory<'a>>,
+    files: BTreeMap<&'a str, u64>,
+    length: u64,
+}
+
+impl<'a> Directory<'a> {
+    fn insert(&mut self, path: &[&'a str], length: u64) {
+        self.length += length;
+
+        match path {
+            [] => {}
+            [file_name] => {
+                self.files.insert(file_name, length);
+            }
+            [directory_name, rest @ ..] => self
+                .directories
+                .entry(directory_name)
+                .or_default()
+                .insert(rest, length),
+        }
+    }
+
+    fn print(&self, indent: usize) {
+        for (name, directory) in &self.directories {
+            println!("{:indent$}{}/ ({})", "", name, human_size(directory.length), indent = indent);
+            directory.print(indent + 2);
+        }
+
+        for (name, length) in &self.files {
+            println!("{:indent$}{} ({})", "", name, human_size(*length), indent = indent);
+        }
+    }
+}
+
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut size = bytes as f64 / 1024.;
+    let mut unit = 0;
+    while size >= 1024. && unit < UNITS.len() - 1 {
+        size /= 1024.;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+// Converts seconds since the UNIX epoch to a UTC date and time, as per http://howardhinnant.github.io/date_algorithms.html
+fn format_timestamp(timestamp: i64) -> String {
+    let days = timestamp.div_euclid(86400);
+    let seconds = timestamp.rem_euclid(86400);
+
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+fn print_human(output: &InspectOutput) {
+    println!("Name:          {}", output.name);
+    println!("Info hash:     {}", output.info_hash.hex);
+    println!("               {}", output.info_hash.base32);
+    println!("Private:       {}", if output.private { "yes" } else { "no" });
+
+    if let Some(comment) = output.comment {
+        println!("Comment:       {}", comment);
+    }
+    if let Some(created_by) = output.created_by {
+        println!("Created by:    {}", created_by);
+    }
+    if let Some(creation_date) = output.creation_date {
+        println!("Creation date: {}", format_timestamp(creation_date));
+    }
+
+    println!(
+        "Piece length:  {} ({} bytes)",
+        human_size(output.piece_length),
+        output.piece_length
+    );
+    println!("Pieces:        {}", output.piece_count);
+    println!(
+        "Total size:    {} ({} bytes)",
+        human_size(output.total_length as u64),
+        output.total_length
+    );
+
+    println!();
+    println!("Trackers:");
+    for (i, tier) in output.trackers.iter().enumerate() {
+        println!("  Tier {}:", i + 1);
+        for tracker in tier {
+            println!("    {}", tracker);
+        }
+    }
+
+    if !output.nodes.is_empty() {
+        println!();
+        println!("DHT nodes:");
+        for node in output.nodes {
+            println!("  {}", node);
+        }
+    }
+
+    println!();
+    println!("Files:");
+    let mut root = Directory::default();
+    for file in &output.files {
+        if output.multi_file {
+            root.insert(&[&[output.name], &file.path[..]].concat(), file.length);
+        } else {
+            root.insert(&file.path, file.length);
+        }
+    }
+    root.print(2);
+}
+
+pub fn inspect(metainfo: &Metainfo, json: bool) -> Result<()> {
+    let output = InspectOutput::new(metainfo);
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&output)?);
+    } else {
+        print_human(&output);
+    }
+
+    Ok(())
+}
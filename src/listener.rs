@@ -0,0 +1,88 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    task::JoinHandle,
+};
+
+use crate::{
+    downloader::{read_handshake, Handshake},
+    metainfo::Sha1Hash,
+    net, ClientConfig,
+};
+
+// A peer that's connected to us and sent its handshake, which is yet to be answered
+pub struct IncomingPeer {
+    pub peer: SocketAddr,
+    pub stream: TcpStream,
+    pub handshake: Handshake,
+}
+
+type Torrents = Arc<Mutex<HashMap<Sha1Hash, mpsc::Sender<IncomingPeer>>>>;
+
+// Accepts connections from peers on our port, and hands each one over to the torrent named in its handshake.
+// Connections for torrents we don't have are dropped.
+pub struct PeerListener {
+    torrents: Torrents,
+    task: JoinHandle<()>,
+}
+
+impl PeerListener {
+    pub fn new(listener: TcpListener, client_config: ClientConfig) -> Self {
+        let torrents = Torrents::default();
+        let task = tokio::spawn(Self::accept_loop(listener, torrents.clone(), client_config));
+
+        Self { torrents, task }
+    }
+
+    pub fn add_torrent(&self, info_hash: Sha1Hash, incoming_tx: mpsc::Sender<IncomingPeer>) {
+        self.torrents.lock().unwrap().insert(info_hash, incoming_tx);
+    }
+
+    async fn accept_loop(listener: TcpListener, torrents: Torrents, client_config: ClientConfig) {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    eprintln!("WARNING: Failed to accept incoming connection: {}", e);
+                    continue;
+                }
+            };
+
+            // Waiting on the handshake mustn't hold up any other connections
+            tokio::spawn(Self::route(stream, net::canonical_addr(peer), torrents.clone(), client_config.clone()));
+        }
+    }
+
+    async fn route(mut stream: TcpStream, peer: SocketAddr, torrents: Torrents, client_config: ClientConfig) {
+        let handshake = match read_handshake(&mut stream, &client_config).await {
+            Ok(handshake) => handshake,
+            Err(e) => {
+                eprintln!("WARNING: Dropping incoming connection from {}: {}", peer, e);
+                return;
+            }
+        };
+
+        let incoming_tx = torrents.lock().unwrap().get(&handshake.info_hash).cloned();
+
+        // If the torrent's full up on incoming connections, the peer will just have to try again later
+        if let Some(incoming_tx) = incoming_tx {
+            let _ = incoming_tx.try_send(IncomingPeer {
+                peer,
+                stream,
+                handshake,
+            });
+        }
+    }
+}
+
+impl Drop for PeerListener {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
@@ -0,0 +1,86 @@
+use std::{collections::HashSet, net::SocketAddr};
+
+use anyhow::{anyhow, Result};
+use data_encoding::{BASE32, HEXLOWER_PERMISSIVE};
+use reqwest::Url;
+
+use crate::metainfo::Sha1Hash;
+
+// A magnet URI, as per BEP 9: magnet:?xt=urn:btih:<info-hash>&dn=<name>&tr=<tracker-url>&x.pe=<peer-address>
+#[derive(Debug, Clone)]
+pub struct MagnetLink {
+    pub info_hash: Sha1Hash,
+    pub display_name: Option<String>,
+    pub trackers: Vec<Url>,
+    // host:port, ipv4:port or [ipv6]:port
+    pub peer_addresses: Vec<String>,
+}
+
+// The info hash may be given as 40 hex digits, or as 32 base32 characters
+fn parse_info_hash(encoded: &str) -> Result<Sha1Hash> {
+    let decoded = match encoded.len() {
+        40 => HEXLOWER_PERMISSIVE.decode(encoded.as_bytes())?,
+        32 => BASE32.decode(encoded.to_ascii_uppercase().as_bytes())?,
+        _ => return Err(anyhow!("Invalid info hash in magnet link: {}", encoded)),
+    };
+
+    Ok(decoded[..].try_into()?)
+}
+
+impl MagnetLink {
+    pub fn parse(uri: &str) -> Result<Self> {
+        let url = Url::parse(uri)?;
+        if url.scheme() != "magnet" {
+            return Err(anyhow!("Not a magnet link: {}", uri));
+        }
+
+        let mut info_hash = None;
+        let mut display_name = None;
+        let mut trackers = Vec::new();
+        let mut peer_addresses = Vec::new();
+
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "xt" => {
+                    // Other kinds of exact topic (e.g. BitTorrent v2's urn:btmh) are ignored
+                    if let Some(encoded) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_info_hash(encoded)?);
+                    }
+                }
+                "dn" => display_name = Some(value.into_owned()),
+                "tr" => match Url::parse(&value) {
+                    Ok(tracker) => trackers.push(tracker),
+                    Err(e) => eprintln!("WARNING: Skipping invalid tracker URL {} in magnet link: {}", value, e),
+                },
+                "x.pe" => peer_addresses.push(value.into_owned()),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            info_hash: info_hash.ok_or_else(|| anyhow!("Magnet link has no BitTorrent info hash"))?,
+            display_name,
+            trackers,
+            peer_addresses,
+        })
+    }
+
+    // Each tracker is put in its own tier, so they're tried in the order given
+    pub fn announce_list(&self) -> Vec<Vec<Url>> {
+        self.trackers.iter().map(|tracker| vec![tracker.clone()]).collect()
+    }
+
+    // Looks up the addresses of the peers given in the link, skipping any that can't be resolved
+    pub async fn resolve_peers(&self) -> HashSet<SocketAddr> {
+        let mut peers = HashSet::new();
+
+        for address in &self.peer_addresses {
+            match tokio::net::lookup_host(address).await {
+                Ok(addrs) => peers.extend(addrs),
+                Err(e) => eprintln!("WARNING: Skipping peer {} from magnet link: {}", address, e),
+            }
+        }
+
+        peers
+    }
+}
@@ -1,13 +1,17 @@
-use std::{time::Duration, path::PathBuf};
+use std::{path::PathBuf, sync::Arc, time::Duration};
 
 use anyhow::Result;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use downloader::Downloader;
 use rand::{
     prelude::{Distribution, SliceRandom},
     Rng,
 };
 
+use announcer::{Announcer, TransferStats};
+use dht::Dht;
+use listener::PeerListener;
+use magnet::MagnetLink;
 use metainfo::Metainfo;
 use peer_list::PeerList;
 
@@ -15,6 +19,18 @@ mod bencode;
 mod metainfo;
 mod peer_list;
 mod downloader;
+mod inspect;
+mod create;
+mod extensions;
+mod magnet;
+mod metadata;
+mod pex;
+mod dht;
+mod net;
+mod announcer;
+mod scrape;
+mod listener;
+mod choker;
 
 struct Digits;
 
@@ -29,20 +45,101 @@ pub type PeerID = [u8; 20];
 #[derive(Parser, Debug)]
 #[clap(version, about)]
 struct Args {
-    /// Path to the metainfo of the torrent to be downloaded
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Download a torrent
+    Download(DownloadArgs),
+    /// Print the contents of a metainfo file
+    Inspect(InspectArgs),
+    /// Create a metainfo file from a file or directory
+    Create(CreateArgs),
+    /// Get seeder and leecher counts for torrents from their trackers, without joining their swarms
+    Scrape(ScrapeArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct CreateArgs {
+    /// Path to the file or directory to be shared
+    pub path: PathBuf,
+
+    /// Where to write the metainfo file
+    #[clap(short, long)]
+    pub output: PathBuf,
+
+    /// A comma-separated tier of tracker URLs. Give this once per tier, in order of preference
+    #[clap(short, long = "tracker", required = true)]
+    pub trackers: Vec<String>,
+
+    /// The piece length in bytes, which must be a power of two. Picked based on the size of the torrent if not given
+    #[clap(short, long)]
+    pub piece_length: Option<u64>,
+
+    /// A free-form comment to include in the metainfo file
+    #[clap(short, long)]
+    pub comment: Option<String>,
+
+    /// Mark the torrent as private, restricting peer discovery to its trackers
+    #[clap(long)]
+    pub private: bool,
+
+    /// Leave out the creation date, so that creating a torrent from the same files twice gives identical output
+    #[clap(long)]
+    pub no_creation_date: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct InspectArgs {
+    /// Path to the metainfo file to be inspected
     pub metainfo_file: PathBuf,
 
+    /// Print the metainfo as JSON, rather than in a human-readable format
+    #[clap(long)]
+    pub json: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct ScrapeArgs {
+    /// Paths to the metainfo of the torrents to be scraped, or magnet links
+    #[clap(required = true)]
+    pub torrents: Vec<String>,
+
+    /// Timeout (in seconds) for each HTTP tracker's response. UDP trackers are retried on their own schedule
+    #[clap(short, long, default_value_t=5.)]
+    pub timeout: f32,
+
+    /// How many times (up to 8) an unanswered request to a UDP tracker is sent again, waiting twice as long each time, from 15 seconds
+    #[clap(long, default_value_t=peer_list::DEFAULT_UDP_RETRANSMISSIONS, value_parser=clap::value_parser!(u32).range(..=peer_list::MAX_UDP_RETRANSMISSIONS as i64))]
+    pub udp_retransmissions: u32,
+
+    /// Print the results as JSON, rather than in a human-readable format
+    #[clap(long)]
+    pub json: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct DownloadArgs {
+    /// Path to the metainfo of the torrent to be downloaded, or a magnet link
+    pub torrent: String,
+
     /// The output directory for the downloaded torrent
     pub download_dir: std::path::PathBuf,
     
-    /// Port reported to trackers as our incoming traffic port. Not currently used.
+    /// Port to listen on for connections from peers, which the DHT node also listens on (over UDP)
     #[clap(short, long, default_value_t=6881)]
     pub port: u16,
 
-    /// Timeout (in seconds) for network-related operations
+    /// Timeout (in seconds) for network-related operations, other than requests to UDP trackers (which are retried on their own schedule)
     #[clap(short, long, default_value_t=2.)]
     pub timeout: f32,
 
+    /// How many times (up to 8) an unanswered request to a UDP tracker is sent again, waiting twice as long each time, from 15 seconds
+    #[clap(long, default_value_t=peer_list::DEFAULT_UDP_RETRANSMISSIONS, value_parser=clap::value_parser!(u32).range(..=peer_list::MAX_UDP_RETRANSMISSIONS as i64))]
+    pub udp_retransmissions: u32,
+
     /// The maximum number of active connections with peers held open simultaneously
     #[clap(short, long, default_value_t=8)]
     pub active_peers: usize,
@@ -50,6 +147,26 @@ struct Args {
     /// The interval (in seconds) at which new active peers are selected to fill any vacancies.
     #[clap(short='u', long, default_value_t=5.)]
     pub peer_update_interval: f32,
+
+    /// The number of peers we upload to at once, one of which is passed around the others in turn (the optimistic unchoke)
+    #[clap(short='s', long, default_value_t=4)]
+    pub upload_slots: usize,
+
+    /// Announce to every tracker at once, rather than trying each tier of trackers in turn until one responds
+    #[clap(long)]
+    pub announce_to_all: bool,
+
+    /// Don't look for peers on the DHT
+    #[clap(long)]
+    pub no_dht: bool,
+
+    /// A comma-separated list of DHT nodes (as host:port) to join the DHT through
+    #[clap(long, value_delimiter = ',', default_values = dht::DEFAULT_BOOTSTRAP_NODES)]
+    pub dht_bootstrap: Vec<String>,
+
+    /// Where the DHT node's ID and routing table are kept between runs [default: .downpour-dht in the download directory]
+    #[clap(long)]
+    pub dht_state: Option<PathBuf>,
 }
 
 #[derive(Clone)]
@@ -57,39 +174,166 @@ pub struct ClientConfig {
     pub peer_id: PeerID,
     pub port: u16,
     pub timeout: std::time::Duration,
+    pub udp_retransmissions: u32,
     pub active_peers: usize,
     pub peer_update_interval: std::time::Duration,
+    pub upload_slots: usize,
     pub download_dir: PathBuf,
+    pub announce_to_all: bool,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    match Args::parse().command {
+        Command::Download(args) => download(args).await,
+        Command::Inspect(args) => inspect::inspect(&Metainfo::from_file(args.metainfo_file)?, args.json),
+        Command::Create(args) => create(args),
+        Command::Scrape(args) => {
+            scrape::scrape(&args.torrents, Duration::from_secs_f32(args.timeout), args.udp_retransmissions, args.json).await
+        },
+    }
+}
+
+fn create(args: CreateArgs) -> Result<()> {
+    let options = create::CreateOptions {
+        trackers: args
+            .trackers
+            .iter()
+            .map(|tier| tier.split(',').map(|url| url.trim().to_string()).collect())
+            .collect(),
+        piece_length: args.piece_length,
+        comment: args.comment,
+        created_by: Some(format!("downpour/{}", env!("CARGO_PKG_VERSION"))),
+        creation_date: !args.no_creation_date,
+        private: args.private,
+    };
+
+    let metainfo = create::create_to_file(&args.path, &args.output, &options)?;
+    println!("Created {} with info_hash {}", args.output.display(), data_encoding::HEXLOWER.encode(&metainfo.info_hash));
+
+    Ok(())
+}
+
+async fn download(args: DownloadArgs) -> Result<()> {
     let random_digits_string: String = rand::thread_rng().sample_iter(&Digits).take(12).collect();
     let peer_id = "-DO0001-".to_string() + &random_digits_string;
 
-    let args = Args::parse();
-
     // TODO: CLI args, config file - some other abstraction
     let client_config = ClientConfig {
         peer_id: peer_id.as_bytes().try_into()?,
         port: args.port,
         timeout: Duration::from_secs_f32(args.timeout),
+        udp_retransmissions: args.udp_retransmissions,
         active_peers: args.active_peers,
         peer_update_interval: Duration::from_secs_f32(args.peer_update_interval),
-        download_dir: args.download_dir.into(),
+        upload_slots: args.upload_slots,
+        download_dir: args.download_dir.clone(),
+        announce_to_all: args.announce_to_all,
+    };
+
+    let (metainfo, peers, announcer, stats) = if args.torrent.starts_with("magnet:") {
+        let magnet = MagnetLink::parse(&args.torrent)?;
+        let announce_list = magnet.announce_list();
+        let dht = start_dht(&args, &[]).await;
+
+        // We can't know how much there is left to download until we have the metadata, but reporting
+        // nothing left would have trackers treat us as a seed, and only tell us about other leechers
+        let stats = Arc::new(TransferStats::new(1));
+        let mut announcer = Announcer::new(&announce_list, dht, magnet.info_hash, stats.clone(), client_config.clone());
+
+        let mut peers = PeerList(announcer.announce_started().await);
+        peers.0.extend(magnet.resolve_peers().await);
+
+        if peers.0.is_empty() {
+            eprintln!("Unable to source any peers; exiting.");
+            return Ok(());
+        }
+
+        if let Some(name) = &magnet.display_name {
+            println!("Fetching metadata for {}", name);
+        }
+
+        let info_bytes = metadata::fetch_metadata(&magnet.info_hash, &peers, &client_config).await?;
+        let metainfo = Metainfo::from_info_bytes(&info_bytes, &magnet.info_hash, announce_list)?;
+
+        // We only find out the torrent is private once we have its metadata, but from then on,
+        // its peers must only come from its trackers (BEP 27)
+        if metainfo.private {
+            announcer.disable_dht();
+        }
+
+        (metainfo, peers, announcer, stats)
+    } else {
+        let metainfo = Metainfo::from_file(&args.torrent)?;
+
+        // Private torrents must only get their peers from their trackers (BEP 27)
+        let dht = match metainfo.private {
+            true => None,
+            false => start_dht(&args, &metainfo.nodes).await,
+        };
+
+        let stats = Arc::new(TransferStats::new(metainfo.total_length as u64));
+        let mut announcer = Announcer::new(&metainfo.announce_list, dht, metainfo.info_hash, stats.clone(), client_config.clone());
+
+        let peers = PeerList(announcer.announce_started().await);
+
+        // We'll keep announcing in the background, so there may yet be peers to come
+        if peers.0.is_empty() {
+            eprintln!("WARNING: No peers found yet; waiting for more from the trackers.");
+        }
+
+        (metainfo, peers, announcer, stats)
     };
 
-    let metainfo = Metainfo::from_file(args.metainfo_file)?;
-    let peers = PeerList::fetch_peers_from_metainfo(&metainfo, &client_config).await;
+    let info_hash = metainfo.info_hash;
+    let downloader = Downloader::new(metainfo, peers, stats, client_config.clone());
+    let announcer = announcer.spawn(downloader.peer_sender());
+
+    // Not being able to take incoming connections only costs us the peers that can't accept ours
+    let _listener = match net::bind_tcp_dual_stack(args.port) {
+        Ok(listener) => {
+            let listener = PeerListener::new(listener, client_config);
+            listener.add_torrent(info_hash, downloader.incoming_sender());
+            Some(listener)
+        }
+        Err(e) => {
+            eprintln!("WARNING: Unable to listen for incoming connections on port {}: {}", args.port, e);
+            None
+        }
+    };
 
-    if peers.0.is_empty() {
-        eprintln!("Unable to source any peers; exiting.");
-        return Ok(());
+    // Stopping on Ctrl-C, rather than being killed outright, lets us tell the trackers we're leaving,
+    // and gives the DHT node the chance to save its state
+    tokio::select! {
+        result = downloader.download() => result?,
+        _ = tokio::signal::ctrl_c() => println!("Interrupted; shutting down."),
     }
 
-    Downloader::new(metainfo, peers, client_config)
-        .download()
-        .await?;
+    announcer.stop().await;
 
     Ok(())
 }
+
+// Starts a DHT node on our port and joins the DHT, unless it's been disabled.
+// extra_nodes are bootstrapped from alongside the usual bootstrap nodes, such as those in a trackerless torrent.
+async fn start_dht(args: &DownloadArgs, extra_nodes: &[String]) -> Option<Dht> {
+    if args.no_dht {
+        return None;
+    }
+
+    let state_file = args.dht_state.clone().unwrap_or_else(|| args.download_dir.join(".downpour-dht"));
+
+    let dht = match net::bind_udp_dual_stack(args.port).and_then(|socket| Dht::new(socket, Some(state_file))) {
+        Ok(dht) => dht,
+        Err(e) => {
+            eprintln!("WARNING: Unable to start the DHT node: {}", e);
+            return None;
+        }
+    };
+
+    let bootstrap_nodes: Vec<String> = extra_nodes.iter().chain(&args.dht_bootstrap).cloned().collect();
+    dht.bootstrap(&bootstrap_nodes).await;
+    println!("Joined the DHT with {} nodes in the routing table", dht.node_count());
+
+    Some(dht)
+}
@@ -0,0 +1,218 @@
+use std::net::SocketAddr;
+
+use anyhow::{anyhow, Result};
+use binwrite::BinWrite;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::oneshot,
+};
+
+use crate::{
+    bencode::{self, ParseOptions},
+    downloader::{connect_to_peer, read_packets, Packet},
+    extensions::{has_extension_bit, set_extension_bit, Extension, ExtendedHandshake, ExtensionRegistry},
+    metainfo::Sha1Hash,
+    peer_list::PeerList,
+    ClientConfig,
+};
+
+// The info dict is exchanged in pieces of this many bytes (bar the last)
+const METADATA_PIECE_LENGTH: usize = 1 << 14;
+// An upper bound on the size of info dict we're willing to accept from a peer
+const MAX_METADATA_SIZE: usize = 1 << 24;
+
+#[derive(Serialize, Deserialize)]
+struct MetadataMessage {
+    // 0 for a request, 1 for data, 2 for a rejection
+    msg_type: u8,
+    piece: usize,
+    total_size: Option<usize>,
+}
+
+// The ut_metadata extension (BEP 9), used to download the info dict of a torrent from a peer.
+// Once the whole info dict has arrived and matches info_hash, it's sent down the result channel.
+struct MetadataFetch {
+    info_hash: Sha1Hash,
+    metadata: Vec<u8>,
+    pieces_received: Vec<bool>,
+    result: Option<oneshot::Sender<Vec<u8>>>,
+}
+
+impl MetadataFetch {
+    fn new(info_hash: Sha1Hash, result: oneshot::Sender<Vec<u8>>) -> Self {
+        Self {
+            info_hash,
+            metadata: Vec::new(),
+            pieces_received: Vec::new(),
+            result: Some(result),
+        }
+    }
+}
+
+impl Extension for MetadataFetch {
+    fn name(&self) -> &'static str {
+        "ut_metadata"
+    }
+
+    // Request every piece of the info dict up front
+    fn on_handshake(&mut self, handshake: &ExtendedHandshake) -> Result<Vec<Vec<u8>>> {
+        let metadata_size = match handshake.metadata_size {
+            Some(size) if size > 0 && size <= MAX_METADATA_SIZE => size,
+            Some(size) => return Err(anyhow!("Peer reported an invalid metadata size of {}", size)),
+            None => return Err(anyhow!("Peer did not report the size of the metadata")),
+        };
+
+        let num_pieces = metadata_size.div_ceil(METADATA_PIECE_LENGTH);
+        self.metadata = vec![0u8; metadata_size];
+        self.pieces_received = vec![false; num_pieces];
+
+        (0..num_pieces)
+            .map(|piece| {
+                let request = MetadataMessage {
+                    msg_type: 0,
+                    piece,
+                    total_size: None,
+                };
+                Ok(bencode::to_bytes(&request)?)
+            })
+            .collect()
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>> {
+        // The piece data (if any) comes directly after the bencoded dictionary
+        let (piece_data, message) = bencode::parse_bencode(payload, &ParseOptions::default())
+            .map_err(|_| anyhow!("Invalid ut_metadata message from peer"))?;
+        let message: MetadataMessage = bencode::from_ref(&message)?;
+
+        match message.msg_type {
+            1 => {
+                // The piece number comes from the peer, so it's checked before anything's computed from it
+                if message.piece >= self.pieces_received.len() {
+                    return Err(anyhow!("Peer sent an invalid metadata piece {}", message.piece));
+                }
+
+                let start = message.piece * METADATA_PIECE_LENGTH;
+                let expected_len = std::cmp::min(METADATA_PIECE_LENGTH, self.metadata.len().saturating_sub(start));
+
+                if piece_data.len() != expected_len {
+                    return Err(anyhow!("Peer sent an invalid metadata piece {}", message.piece));
+                }
+
+                self.metadata[start..start + expected_len].copy_from_slice(piece_data);
+                self.pieces_received[message.piece] = true;
+
+                if !self.pieces_received.contains(&false) {
+                    let mut hasher = Sha1::new();
+                    hasher.update(&self.metadata);
+                    if hasher.finalize()[..] != self.info_hash[..] {
+                        return Err(anyhow!("Metadata from peer does not match the info hash"));
+                    }
+
+                    if let Some(result) = self.result.take() {
+                        // The receiver only goes away once it's given up on this peer
+                        let _ = result.send(std::mem::take(&mut self.metadata));
+                    }
+                }
+            }
+            2 => return Err(anyhow!("Peer rejected our request for metadata piece {}", message.piece)),
+            // We don't have the metadata to share, so requests go unanswered
+            _ => {}
+        }
+
+        Ok(Vec::new())
+    }
+}
+
+// Waits for the next message from the peer that we understand
+async fn next_packet(stream: &mut TcpStream, data_buf: &mut Vec<u8>, client_config: &ClientConfig) -> Result<Packet> {
+    loop {
+        // Packets are taken from the buffer one at a time, so anything after the first is left there for the next call
+        while data_buf.len() >= 4 {
+            let packet_len = u32::from_be_bytes(data_buf[..4].try_into()?) as usize + 4;
+            if data_buf.len() < packet_len {
+                break;
+            }
+
+            let mut packet_buf: Vec<u8> = data_buf.drain(..packet_len).collect();
+            // Unknown messages from peers are no reason to abandon the metadata exchange
+            if let Ok(packets) = read_packets(&mut packet_buf) {
+                if let Some(packet) = packets.into_iter().next() {
+                    return Ok(packet);
+                }
+            }
+        }
+
+        let mut buf = [0u8; 4096];
+        let buf_len = tokio::time::timeout(client_config.timeout, stream.read(&mut buf)).await??;
+        if buf_len == 0 {
+            return Err(anyhow!("Connection closed by peer"));
+        }
+
+        data_buf.extend(&buf[..buf_len]);
+    }
+}
+
+// Fetches the info dict of a torrent from a single peer
+async fn fetch_metadata_from_peer(
+    peer: SocketAddr,
+    info_hash: &Sha1Hash,
+    client_config: &ClientConfig,
+) -> Result<Vec<u8>> {
+    let mut reserved = [0u8; 8];
+    set_extension_bit(&mut reserved);
+
+    let (mut stream, handshake) = connect_to_peer(peer, info_hash, client_config, reserved).await?;
+    let mut data_buf = Vec::new();
+
+    if !has_extension_bit(&handshake.reserved) {
+        return Err(anyhow!("Peer does not support the extension protocol"));
+    }
+
+    let (result_tx, mut result_rx) = oneshot::channel();
+    let mut extensions = ExtensionRegistry::new(vec![Box::new(MetadataFetch::new(*info_hash, result_tx))]);
+
+    let mut bytes = vec![];
+    extensions.handshake(peer, client_config)?.write(&mut bytes)?;
+    stream.write_all(&bytes).await?;
+
+    loop {
+        if let Packet::Extended(packet) = next_packet(&mut stream, &mut data_buf, client_config).await? {
+            let mut bytes = vec![];
+            for response in extensions.handle(packet)? {
+                response.write(&mut bytes)?;
+            }
+            stream.write_all(&bytes).await?;
+
+            if extensions.their_handshake().is_some() && !extensions.peer_supports("ut_metadata") {
+                return Err(anyhow!("Peer does not support ut_metadata"));
+            }
+
+            if let Ok(metadata) = result_rx.try_recv() {
+                return Ok(metadata);
+            }
+        }
+    }
+}
+
+// Fetches the info dict of a torrent from whichever of the given peers can provide it first
+pub async fn fetch_metadata(info_hash: &Sha1Hash, peers: &PeerList, client_config: &ClientConfig) -> Result<Vec<u8>> {
+    let mut attempts = futures::stream::iter(peers.0.iter().copied())
+        .map(|peer| async move { (peer, fetch_metadata_from_peer(peer, info_hash, client_config).await) })
+        .buffer_unordered(client_config.active_peers);
+
+    while let Some((peer, result)) = attempts.next().await {
+        match result {
+            Ok(metadata) => {
+                println!("Fetched metadata from {}", peer);
+                return Ok(metadata);
+            }
+            Err(e) => eprintln!("Failed to fetch metadata from {}: {}", peer, e),
+        }
+    }
+
+    Err(anyhow!("Unable to fetch the torrent's metadata from any peer"))
+}
@@ -1,19 +1,49 @@
 use anyhow::{anyhow, Result};
 use reqwest::Url;
+use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
 use std::path::Path;
 
-use crate::bencode::{self, BencodeValue};
+use crate::bencode::{self, BencodeRef, ParseOptions};
 
 pub type Sha1Hash = [u8; 20];
 
+// The metainfo file as it's laid out on disk. Missing optional fields are left out entirely when serialized.
+#[derive(Serialize, Deserialize)]
+pub struct MetainfoFile<'a> {
+    pub announce: Option<String>,
+    #[serde(rename = "announce-list")]
+    pub announce_list: Option<Vec<Vec<String>>>,
+    pub comment: Option<String>,
+    #[serde(rename = "created by")]
+    pub created_by: Option<String>,
+    #[serde(rename = "creation date")]
+    pub creation_date: Option<i64>,
+    #[serde(borrow)]
+    pub info: InfoDict<'a>,
+    // DHT nodes for trackerless torrents, as (host, port) pairs
+    pub nodes: Option<Vec<(String, u16)>>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct InfoDict<'a> {
+    pub name: String,
+    #[serde(rename = "piece length")]
+    pub piece_length: u64,
+    #[serde(with = "serde_bytes")]
+    pub pieces: &'a [u8],
+    pub length: Option<u64>,
+    pub files: Option<Vec<DirectoryFileInfo>>,
+    pub private: Option<i64>,
+}
+
 #[derive(Debug, Clone)]
 pub struct SingleFileInfo {
     pub name: String,
     pub length: u64,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DirectoryFileInfo {
     pub path: Vec<String>,
     pub length: u64,
@@ -33,147 +63,266 @@ pub enum Info {
 
 #[derive(Debug, Clone)]
 pub struct Metainfo {
-    pub announce_list: Vec<Url>,
+    // Trackers, grouped into tiers. This may be empty for trackerless torrents, whose peers are found through the DHT.
+    pub announce_list: Vec<Vec<Url>>,
+    // DHT nodes to bootstrap from, as host:port
+    pub nodes: Vec<String>,
     pub piece_length: u64,
     pub pieces: Vec<Sha1Hash>,
     pub total_length: usize,
     pub info: Info,
     pub info_hash: Sha1Hash,
+    pub private: bool,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    // Seconds since the UNIX epoch
+    pub creation_date: Option<i64>,
+}
+
+// Plenty of torrents in the wild aren't canonically encoded, so these are accepted with a warning
+fn decode_leniently<'a>(bytes: &'a [u8], description: &str) -> Result<BencodeRef<'a>> {
+    match bencode::decode(bytes, &ParseOptions::strict()) {
+        Ok(root) => Ok(root),
+        Err(e) => {
+            let root = bencode::decode(bytes, &ParseOptions::default())
+                .map_err(|e| anyhow!("Invalid {}: {}", description, e))?;
+            eprintln!("WARNING: {} is not canonically encoded ({})", description, e);
+            Ok(root)
+        }
+    }
 }
 
 impl Metainfo {
+    // The length in bytes of the given piece. Every piece but the last is piece_length long.
+    pub fn length_of_piece(&self, piece_index: usize) -> usize {
+        if piece_index + 1 == self.pieces.len() {
+            self.total_length - (self.pieces.len() - 1) * self.piece_length as usize
+        } else {
+            self.piece_length as usize
+        }
+    }
+
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
         let bytes = std::fs::read(path)?;
         Self::from_bytes(bytes)
     }
 
     pub fn from_bytes(bytes: Vec<u8>) -> Result<Self> {
-        let (_, val) =
-            bencode::parse_bencode(&bytes).map_err(|_| anyhow!("Bencode parse error"))?;
-
-        // The root-level element of a metainfo file needs to be a dictionary
-        let root = val.as_dict()?;
-
-        let announce_list =
-            if let Some(BencodeValue::List(announce_list)) = root.get("announce-list".as_bytes()) {
-                // TODO: rewrite this using iter chains
-                let mut announce_urls = Vec::new();
-
-                for tier_list in announce_list {
-                    // TODO: the trackers in a tier are meant to be shuffled randomly.
-                    for announce_val in tier_list.as_list()? {
-                        announce_urls.push(Url::parse(announce_val.as_str()?)?);
-                    }
-                }
-
-                announce_urls
-            } else {
-                let announce_string = root
-                    .get("announce".as_bytes())
-                    .ok_or_else(|| anyhow!("Invalid metainfo file: no announce URL"))?
-                    .as_str()?;
-
-                vec![Url::parse(announce_string)?]
-            };
-
-        let info_dict = root
-            .get("info".as_bytes())
-            .ok_or_else(|| anyhow!("Invalid metainfo file: no info dict"))?
-            .as_dict()?;
-
-        let name = info_dict
-            .get("name".as_bytes())
-            .ok_or_else(|| anyhow!("Invalid info dict: no name"))?
-            .as_str()?;
-
-        let piece_length: u64 = info_dict
-            .get("piece length".as_bytes())
-            .ok_or_else(|| anyhow!("Invalid info dict: no piece length"))?
-            .as_integer()?
-            .try_into()?;
-
-        let pieces_bytestring = info_dict
-            .get("pieces".as_bytes())
-            .ok_or_else(|| anyhow!("Invalid info dict: no pieces bytestring"))?
-            .as_bytes()?;
-
-        let pieces_slices: Vec<&[u8]> = pieces_bytestring.chunks(20).collect();
-
-        let pieces = pieces_slices
+        let root = decode_leniently(&bytes, "metainfo file")?;
+
+        let metainfo_file: MetainfoFile = bencode::from_ref(&root)
+            .map_err(|e| anyhow!("Invalid metainfo file: {}", e))?;
+
+        let announce_list = if let Some(announce_list) = metainfo_file.announce_list {
+            // Trackers are kept in the order given here; it's up to the announcer to shuffle each tier
+            announce_list
+                .iter()
+                .map(|tier| {
+                    tier.iter()
+                        .map(|announce_string| {
+                            Url::parse(announce_string)
+                                .map_err(|e| anyhow!("Invalid metainfo file: announce-list: {}: {}", e, announce_string))
+                        })
+                        .collect::<Result<Vec<Url>>>()
+                })
+                .collect::<Result<Vec<Vec<Url>>>>()?
+        } else if let Some(announce_string) = metainfo_file.announce {
+            vec![vec![Url::parse(&announce_string)
+                .map_err(|e| anyhow!("Invalid metainfo file: announce: {}: {}", e, announce_string))?]]
+        } else {
+            Vec::new()
+        };
+
+        // Calculate the torrent's info_hash as the SHA1 hash of the raw bytes of the info dictionary.
+        // Should a key be duplicated, the last occurrence wins, matching the decoded root dictionary above.
+        let (_, root_raw) = bencode::parse_dictionary_raw(&bytes, &ParseOptions::default())
+            .map_err(|_| anyhow!("Could not get the raw bytes of the info dict"))?;
+
+        let info_dict_bytes = root_raw
             .iter()
-            .cloned()
+            .rev()
+            .find(|(key, _)| *key == b"info")
+            .map(|(_, raw)| *raw)
+            .ok_or_else(|| anyhow!("Invalid metainfo file: no info dict"))?;
+
+        let mut hasher = Sha1::new();
+        hasher.update(info_dict_bytes);
+        let info_hash: Sha1Hash = hasher.finalize()[..].try_into()?;
+
+        Ok(Self {
+            comment: metainfo_file.comment,
+            created_by: metainfo_file.created_by,
+            creation_date: metainfo_file.creation_date,
+            nodes: metainfo_file
+                .nodes
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(host, port)| match host.contains(':') {
+                    // IPv6 addresses need brackets to be told apart from the port
+                    true => format!("[{}]:{}", host, port),
+                    false => format!("{}:{}", host, port),
+                })
+                .collect(),
+            ..Self::from_info_dict(metainfo_file.info, info_hash, announce_list)?
+        })
+    }
+
+    // Builds a Metainfo from a bare info dict, such as one fetched from peers for a magnet link.
+    // The dict is checked against info_hash, as it may have come from an untrusted source.
+    pub fn from_info_bytes(info_bytes: &[u8], info_hash: &Sha1Hash, announce_list: Vec<Vec<Url>>) -> Result<Self> {
+        let mut hasher = Sha1::new();
+        hasher.update(info_bytes);
+        if hasher.finalize()[..] != info_hash[..] {
+            return Err(anyhow!("Info dict does not match its info_hash"));
+        }
+
+        let root = decode_leniently(info_bytes, "info dict")?;
+        let info_dict: InfoDict = bencode::from_ref(&root)
+            .map_err(|e| anyhow!("Invalid info dict: {}", e))?;
+
+        Self::from_info_dict(info_dict, *info_hash, announce_list)
+    }
+
+    fn from_info_dict(info_dict: InfoDict, info_hash: Sha1Hash, announce_list: Vec<Vec<Url>>) -> Result<Self> {
+        let pieces = info_dict
+            .pieces
+            .chunks(20)
             .map(|x| {
                 x.try_into()
-                    .map_err(|_| anyhow!("Failed to convert slice to array"))
+                    .map_err(|_| anyhow!("Invalid info dict: pieces: length is not a multiple of 20"))
             })
             .collect::<Result<Vec<Sha1Hash>>>()?;
 
         // Is this a single file, or are we dealing with a whole-directory torrent?
-        let mut total_length = 0;
-        let info = if let Some(BencodeValue::List(files_list)) = info_dict.get("files".as_bytes()) {
+        let (info, total_length) = if let Some(files) = info_dict.files {
             // Whole-directory torrent
-            let files: Vec<DirectoryFileInfo> = files_list
-                .iter()
-                .map(|file_val| {
-                    let file_dict = file_val.as_dict()?;
-                    let file_length: u64 = file_dict
-                        .get("length".as_bytes())
-                        .ok_or_else(|| anyhow!("Invalid file entry: no length"))?
-                        .as_integer()?
-                        .try_into()?;
-
-                    let file_path = file_dict
-                        .get("path".as_bytes())
-                        .ok_or_else(|| anyhow!("Invalid file entry: no path"))?
-                        .as_list()?
-                        .iter()
-                        .map(|x| Ok(x.as_str()?.to_string()))
-                        .collect::<Result<Vec<String>>>()?;
-
-                    total_length += file_length;
-
-                    Ok(DirectoryFileInfo {
-                        path: file_path,
-                        length: file_length,
-                    })
-                })
-                .collect::<Result<Vec<DirectoryFileInfo>>>()?;
+            let total_length = files.iter().map(|file| file.length).sum();
 
-            Info::Directory(DirectoryInfo {
-                name: name.to_string(),
+            let info = Info::Directory(DirectoryInfo {
+                name: info_dict.name,
                 files,
-            })
+            });
+
+            (info, total_length)
         } else {
             // Single file torrent
-            let length: u64 = info_dict
-                .get("length".as_bytes())
-                .ok_or_else(|| anyhow!("Invalid single-file torrent: no length"))?
-                .as_integer()?
-                .try_into()?;
-
-            total_length = length;
-            
-            Info::SingleFile(SingleFileInfo {
-                name: name.to_string(),
+            let length = info_dict
+                .length
+                .ok_or_else(|| anyhow!("Invalid single-file torrent: no length"))?;
+
+            let info = Info::SingleFile(SingleFileInfo {
+                name: info_dict.name,
                 length,
-            })
-        };
+            });
 
-        // Calculate the torrent's info_hash as the SHA1 hash of the raw bytes of the info dictionary
-        let (_, info_dict_bytes) = bencode::parse_info_dict_raw(&bytes)
-            .map_err(|_| anyhow!("Could not get the raw bytes of the info dict"))?;
+            (info, length)
+        };
 
-        let mut hasher = Sha1::new();
-        hasher.update(info_dict_bytes);
-        let info_hash: Sha1Hash = hasher.finalize()[..].try_into()?;
+        // Every piece but the last is piece_length long, and the last can't be empty
+        if info_dict.piece_length == 0 {
+            return Err(anyhow!("Invalid info dict: piece length is zero"));
+        }
+        let expected_pieces = total_length.div_ceil(info_dict.piece_length);
+        if pieces.len() as u64 != expected_pieces {
+            return Err(anyhow!(
+                "Invalid info dict: {} piece hashes given, but {} bytes in pieces of {} bytes makes {}",
+                pieces.len(),
+                total_length,
+                info_dict.piece_length,
+                expected_pieces
+            ));
+        }
 
         Ok(Self {
             announce_list,
-            piece_length,
+            nodes: Vec::new(),
+            piece_length: info_dict.piece_length,
             pieces,
             total_length: total_length as usize,
             info,
             info_hash,
+            private: info_dict.private == Some(1),
+            comment: None,
+            created_by: None,
+            creation_date: None,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info_dict(name: &str) -> Vec<u8> {
+        format!("d6:lengthi16384e4:name{}:{}12:piece lengthi16384e6:pieces20:", name.len(), name)
+            .into_bytes()
+            .into_iter()
+            .chain([0xab; 20])
+            .chain(*b"e")
+            .collect()
+    }
+
+    #[test]
+    fn duplicated_keys_take_their_last_value() {
+        let bytes: Vec<u8> = [
+            &b"d8:announce22:http://first.example/a7:comment5:first"[..],
+            b"4:info",
+            &info_dict("first"),
+            b"8:announce23:http://second.example/a7:comment6:second4:info",
+            &info_dict("second"),
+            b"e",
+        ]
+        .concat();
+
+        let metainfo = Metainfo::from_bytes(bytes).unwrap();
+
+        assert_eq!(metainfo.comment.as_deref(), Some("second"));
+        assert_eq!(metainfo.announce_list, vec![vec![Url::parse("http://second.example/a").unwrap()]]);
+        assert!(matches!(metainfo.info, Info::SingleFile(SingleFileInfo { ref name, .. }) if name == "second"));
+
+        // The info_hash is of the same info dict that was decoded
+        let mut hasher = Sha1::new();
+        hasher.update(info_dict("second"));
+        assert_eq!(metainfo.info_hash[..], hasher.finalize()[..]);
+    }
+
+    #[test]
+    fn info_hash_ignores_info_appearing_inside_other_values() {
+        // The bytes of an info key turn up in the announce URL first
+        let bytes: Vec<u8> = [&b"d8:announce27:http://example.com/4:info/a4:info"[..], &info_dict("real"), b"e"].concat();
+
+        let metainfo = Metainfo::from_bytes(bytes).unwrap();
+
+        let mut hasher = Sha1::new();
+        hasher.update(info_dict("real"));
+        assert_eq!(metainfo.info_hash[..], hasher.finalize()[..]);
+    }
+
+    fn torrent(length: u64, piece_length: u64, piece_count: usize) -> Vec<u8> {
+        [
+            format!("d4:infod6:lengthi{}e4:name4:file12:piece lengthi{}e6:pieces{}:", length, piece_length, piece_count * 20)
+                .as_bytes(),
+            &vec![0xab; piece_count * 20],
+            b"ee",
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn piece_count_must_match_length() {
+        assert!(Metainfo::from_bytes(torrent(1, 16384, 1)).is_ok());
+        assert!(Metainfo::from_bytes(torrent(16384, 16384, 1)).is_ok());
+        assert!(Metainfo::from_bytes(torrent(16385, 16384, 2)).is_ok());
+
+        // Too many hashes would leave the last pieces with a negative length
+        assert!(Metainfo::from_bytes(torrent(1, 16384, 2)).is_err());
+        assert!(Metainfo::from_bytes(torrent(16384, 16384, 0)).is_err());
+        assert!(Metainfo::from_bytes(torrent(16385, 16384, 1)).is_err());
+    }
+
+    #[test]
+    fn zero_piece_length_is_rejected() {
+        assert!(Metainfo::from_bytes(torrent(1, 0, 1)).is_err());
+        assert!(Metainfo::from_bytes(torrent(0, 0, 0)).is_err());
+    }
+}
@@ -0,0 +1,83 @@
+use std::net::{Ipv6Addr, SocketAddr};
+
+use anyhow::Result;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{TcpListener, UdpSocket};
+
+// Binds a UDP socket to the given port on every interface. Where IPv6 is available, a single IPv6 socket
+// takes both IPv4 and IPv6 traffic; otherwise we fall back to an IPv4-only socket.
+pub fn bind_udp_dual_stack(port: u16) -> Result<UdpSocket> {
+    let dual_stack = || -> Result<UdpSocket> {
+        let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_only_v6(false)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+        Ok(UdpSocket::from_std(socket.into())?)
+    };
+
+    match dual_stack() {
+        Ok(socket) => Ok(socket),
+        Err(_) => {
+            let socket = std::net::UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], port)))?;
+            socket.set_nonblocking(true)?;
+            Ok(UdpSocket::from_std(socket)?)
+        }
+    }
+}
+
+// Likewise for a TCP listener
+pub fn bind_tcp_dual_stack(port: u16) -> Result<TcpListener> {
+    let dual_stack = || -> Result<TcpListener> {
+        let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_only_v6(false)?;
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+        socket.listen(128)?;
+        Ok(TcpListener::from_std(socket.into())?)
+    };
+
+    match dual_stack() {
+        Ok(listener) => Ok(listener),
+        Err(_) => {
+            let listener = std::net::TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], port)))?;
+            listener.set_nonblocking(true)?;
+            Ok(TcpListener::from_std(listener)?)
+        }
+    }
+}
+
+// Dual-stack sockets see IPv4 addresses as IPv4-mapped IPv6 addresses (::ffff:a.b.c.d), which are turned back into IPv4 ones
+pub fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+// The reverse of canonical_addr: IPv6 sockets can only send to IPv6 addresses, so IPv4 ones are mapped into the IPv6 space
+pub fn addr_for_socket(addr: SocketAddr, socket_addr: &SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(v4) if socket_addr.is_ipv6() => SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port()),
+        _ => addr,
+    }
+}
+
+// Whether a socket bound to socket_addr can reach addr
+pub fn can_reach(socket_addr: &SocketAddr, addr: &SocketAddr) -> bool {
+    socket_addr.is_ipv6() || addr.is_ipv4()
+}
+
+// Our globally routable IPv6 address, if we have one. No packets are sent to find it: connecting a UDP socket
+// just has the OS pick the address it would send from.
+pub fn public_ipv6_address() -> Option<Ipv6Addr> {
+    let socket = std::net::UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect(("2001:4860:4860::8888", 80)).ok()?;
+
+    match socket.local_addr().ok()?.ip() {
+        std::net::IpAddr::V6(ip) if is_global_ipv6(&ip) => Some(ip),
+        _ => None,
+    }
+}
+
+// Global unicast addresses are all within 2000::/3
+fn is_global_ipv6(ip: &Ipv6Addr) -> bool {
+    ip.segments()[0] & 0xe000 == 0x2000
+}
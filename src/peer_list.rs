@@ -2,285 +2,602 @@ use binread::BinRead;
 use binwrite::BinWrite;
 
 use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
 use tokio::net::UdpSocket;
 
 use anyhow::{anyhow, Result};
 use rand::Rng;
 use std::{
-    collections::HashSet,
+    collections::{BTreeMap, HashMap, HashSet},
     io::Cursor,
-    net::{SocketAddr, SocketAddrV4},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Mutex,
+    time::{Duration, Instant},
 };
 
 use crate::{
     bencode,
-    metainfo::{Metainfo, Sha1Hash},
-    ClientConfig, PeerID,
+    dht::Dht,
+    metainfo::Sha1Hash,
+    net, ClientConfig, PeerID,
 };
 
+// How long we'll wait on a DHT lookup for peers
+const DHT_LOOKUP_TIMEOUT: Duration = Duration::from_secs(30);
+
 #[derive(Debug)]
 pub struct PeerList(pub HashSet<SocketAddr>);
 
-async fn fetch_peers_http(
+// In compact form, each peer is its IP address (4 bytes for IPv4, 16 for IPv6) followed by its port, all big-endian.
+// Any trailing bytes that don't make up a whole peer are ignored.
+pub fn parse_compact_peers(bytes: &[u8], ipv6: bool) -> Vec<SocketAddr> {
+    let ip_len = if ipv6 { 16 } else { 4 };
+
+    bytes
+        .chunks_exact(ip_len + 2)
+        .map(|chunk| {
+            let (ip, port) = chunk.split_at(ip_len);
+            let port = u16::from_be_bytes([port[0], port[1]]);
+
+            // The unwraps are safe, as the chunks are exactly the right length.
+            // IPv4-mapped IPv6 addresses are turned back into IPv4 ones, so the same peer isn't listed twice.
+            if ipv6 {
+                net::canonical_addr(SocketAddr::new(Ipv6Addr::from(<[u8; 16]>::try_from(ip).unwrap()).into(), port))
+            } else {
+                SocketAddr::new(Ipv4Addr::from(<[u8; 4]>::try_from(ip).unwrap()).into(), port)
+            }
+        })
+        .collect()
+}
+
+pub fn encode_compact_peer(peer: &SocketAddr, out: &mut Vec<u8>) {
+    match peer {
+        SocketAddr::V4(addr) => out.extend(addr.ip().octets()),
+        SocketAddr::V6(addr) => out.extend(addr.ip().octets()),
+    }
+    out.extend(peer.port().to_be_bytes());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    // A regular announce, made once the tracker's interval is up
+    Regular,
+    Started,
+    Completed,
+    Stopped,
+}
+
+impl AnnounceEvent {
+    // The value of the event parameter of HTTP announces, which is left out for regular announces
+    fn http_name(&self) -> Option<&'static str> {
+        match self {
+            AnnounceEvent::Regular => None,
+            AnnounceEvent::Started => Some("started"),
+            AnnounceEvent::Completed => Some("completed"),
+            AnnounceEvent::Stopped => Some("stopped"),
+        }
+    }
+
+    fn udp_id(&self) -> u32 {
+        match self {
+            AnnounceEvent::Regular => 0,
+            AnnounceEvent::Completed => 1,
+            AnnounceEvent::Started => 2,
+            AnnounceEvent::Stopped => 3,
+        }
+    }
+}
+
+// Everything we tell a tracker when we announce to it. All counts are in bytes.
+pub struct AnnounceRequest<'a> {
+    pub info_hash: &'a Sha1Hash,
+    pub event: AnnounceEvent,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    // As given by the tracker in an earlier response, if it gave us one
+    pub tracker_id: Option<&'a [u8]>,
+}
+
+pub struct AnnounceResponse {
+    pub peers: HashSet<SocketAddr>,
+    // How long the tracker would like us to wait before announcing again
+    pub interval: Option<Duration>,
+    // How long we must wait before announcing again, even if we want more peers
+    pub min_interval: Option<Duration>,
+    pub seeders: Option<u32>,
+    pub leechers: Option<u32>,
+    // A message the tracker wants shown to the user, even though the announce succeeded
+    pub warning: Option<String>,
+    // An ID identifying us to an HTTP tracker, to be sent back with our later announces
+    pub tracker_id: Option<Vec<u8>>,
+}
+
+#[derive(Deserialize)]
+struct HttpPeer {
+    ip: String,
+    port: u16,
+}
+
+// Trackers may ignore our request for a compact response, and send a list of dictionaries anyway
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum HttpPeers {
+    Compact(ByteBuf),
+    Dictionaries(Vec<HttpPeer>),
+}
+
+#[derive(Deserialize)]
+struct HttpAnnounceResponse {
+    #[serde(rename = "warning message")]
+    warning_message: Option<String>,
+    // Both in seconds
+    interval: Option<u64>,
+    #[serde(rename = "min interval")]
+    min_interval: Option<u64>,
+    #[serde(rename = "tracker id")]
+    tracker_id: Option<ByteBuf>,
+    // The number of seeders and leechers respectively
+    complete: Option<u32>,
+    incomplete: Option<u32>,
+    peers: Option<HttpPeers>,
+    // IPv6 peers, which only ever come in compact form
+    peers6: Option<ByteBuf>,
+}
+
+// When a tracker refuses a request, it sends this in place of the usual response
+#[derive(Deserialize)]
+struct HttpFailureResponse {
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+}
+
+// Makes a request to an HTTP tracker, returning the body of its response.
+// Trackers can refuse requests with or without an error status, so we look for a failure reason either way.
+async fn http_tracker_request(url: Url) -> Result<Vec<u8>> {
+    let response = reqwest::get(url).await?;
+    let status = response.status();
+    let body = response.bytes().await?.to_vec();
+
+    if let Ok(HttpFailureResponse {
+        failure_reason: Some(failure_reason),
+    }) = bencode::from_bytes(&body)
+    {
+        return Err(anyhow!("Tracker refused the request: {}", failure_reason));
+    }
+
+    if !status.is_success() {
+        return Err(anyhow!("Tracker responded with HTTP status {}", status));
+    }
+
+    Ok(body)
+}
+
+async fn announce_http(
     mut url: Url,
-    metainfo: &Metainfo,
+    request: &AnnounceRequest<'_>,
     client_config: &ClientConfig,
-) -> HashSet<SocketAddr> {
+) -> Result<AnnounceResponse> {
     // We need to build up the query manually like this as Reqwest's in-built
     // urlencoding doesn't support encoding u8 slices.
     let mut query = String::new();
     query += "info_hash=";
-    query += &urlencoding::encode_binary(&metainfo.info_hash);
+    query += &urlencoding::encode_binary(request.info_hash);
 
     url.set_query(Some(&query));
 
-    let peer_list: Result<_> = async {
-        url.query_pairs_mut()
-            .append_pair("peer_id", std::str::from_utf8(&client_config.peer_id)?)
-            .append_pair("port", &client_config.port.to_string())
-            .append_pair("uploaded", "0")
-            .append_pair("downloaded", "0")
-            .append_pair("left", &metainfo.total_length.to_string());
-
-        let res = reqwest::get(url).await?.bytes().await?;
-
-        // TODO: compact format
-        let (_, peers) =
-            bencode::parse_bencode(&res).map_err(|_| anyhow!("Bencode parse error"))?;
-
-        let peer_list = peers
-            .as_dict()?
-            .get("peers".as_bytes())
-            .ok_or_else(|| anyhow!("Response contains no peers"))?
-            .as_list()?
-            .iter()
-            .map(|peer| {
-                let peer = peer.as_dict()?;
-                let ip = peer
-                    .get("ip".as_bytes())
-                    .ok_or_else(|| anyhow!("Peer has no IP field"))?
-                    .as_str()?;
-
-                let port = peer
-                    .get("port".as_bytes())
-                    .ok_or_else(|| anyhow!("Peer has no port field"))?
-                    .as_integer()?;
-
-                Ok(SocketAddr::new(ip.parse()?, port.try_into()?))
-            })
-            .collect::<Result<HashSet<SocketAddr>>>()?;
-
-        Ok(peer_list)
+    url.query_pairs_mut()
+        .append_pair("peer_id", std::str::from_utf8(&client_config.peer_id)?)
+        .append_pair("port", &client_config.port.to_string())
+        .append_pair("uploaded", &request.uploaded.to_string())
+        .append_pair("downloaded", &request.downloaded.to_string())
+        .append_pair("left", &request.left.to_string())
+        // BEP 23
+        .append_pair("compact", "1");
+
+    if let Some(event) = request.event.http_name() {
+        url.query_pairs_mut().append_pair("event", event);
     }
-    .await;
 
-    match peer_list {
-        Ok(peer_list) => peer_list,
-        Err(e) => {
-            eprintln!("Skipping tracker due to error: {}", e);
-            HashSet::new()
-        }
+    // BEP 7: when announcing over IPv4, this lets the tracker hand out our IPv6 address too
+    if let Some(ipv6) = net::public_ipv6_address() {
+        url.query_pairs_mut().append_pair("ipv6", &ipv6.to_string());
+    }
+
+    // Tracker IDs are byte strings too, so need the same manual encoding as the info hash
+    if let Some(tracker_id) = request.tracker_id {
+        let query = format!("{}&trackerid={}", url.query().unwrap_or_default(), urlencoding::encode_binary(tracker_id));
+        url.set_query(Some(&query));
+    }
+
+    let res = http_tracker_request(url).await?;
+
+    let response: HttpAnnounceResponse = bencode::from_bytes(&res)?;
+
+    let mut peer_list = match response.peers {
+        Some(HttpPeers::Compact(peers)) => parse_compact_peers(&peers, false).into_iter().collect(),
+        Some(HttpPeers::Dictionaries(peers)) => peers
+            .into_iter()
+            .map(|peer| Ok(SocketAddr::new(peer.ip.parse()?, peer.port)))
+            .collect::<Result<HashSet<SocketAddr>>>()?,
+        None => HashSet::new(),
+    };
+
+    if let Some(peers6) = response.peers6 {
+        peer_list.extend(parse_compact_peers(&peers6, true));
     }
+
+    Ok(AnnounceResponse {
+        peers: peer_list,
+        interval: response.interval.map(Duration::from_secs),
+        min_interval: response.min_interval.map(Duration::from_secs),
+        seeders: response.complete,
+        leechers: response.incomplete,
+        warning: response.warning_message,
+        tracker_id: response.tracker_id.map(ByteBuf::into_vec),
+    })
 }
 
+// BEP 15 has us wait 15 * 2^n seconds for a response before sending a request again, for n up to 8.
+// That's over two hours in all, so by default we give up sooner, rather than have one dead tracker hold up the rest.
+const UDP_BASE_TIMEOUT: Duration = Duration::from_secs(15);
+pub const DEFAULT_UDP_RETRANSMISSIONS: u32 = 2;
+pub const MAX_UDP_RETRANSMISSIONS: u32 = 8;
+// A connection ID can be used for a minute after it's handed out
+const UDP_CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+// Sent in place of a connection ID when asking for one
+const UDP_PROTOCOL_ID: u64 = 0x41727101980;
+// Large enough for any UDP packet
+const UDP_MAX_PACKET_SIZE: usize = 65536;
+
+const UDP_ACTION_CONNECT: u32 = 0;
+const UDP_ACTION_ANNOUNCE: u32 = 1;
+const UDP_ACTION_SCRAPE: u32 = 2;
+const UDP_ACTION_ERROR: u32 = 3;
+
+// The connection IDs UDP trackers have given us, and when, keyed by tracker address.
+// These outlive any one request, so that announcing to and scraping the same tracker doesn't need a new one each time.
+static UDP_CONNECTION_IDS: Mutex<BTreeMap<SocketAddr, (u64, Instant)>> = Mutex::new(BTreeMap::new());
+
+// Every request starts with this, and is followed by the action's own fields
 #[derive(BinWrite, Debug)]
 #[binwrite(big)]
-struct UDPConnectRequest {
-    pub magic: u64,
+struct UDPRequestHeader {
+    pub connection_id: u64,
     pub action: u32,
     pub transaction_id: u32,
 }
 
+// Likewise for every response. Error responses are followed by a message.
 #[derive(BinRead, Debug)]
 #[br(big)]
-struct UDPConnectResponse {
+struct UDPResponseHeader {
     pub action: u32,
     pub transaction_id: u32,
+}
+
+// The size of UDPResponseHeader
+const UDP_RESPONSE_HEADER_LENGTH: usize = 8;
+
+#[derive(BinRead, Debug)]
+#[br(big)]
+struct UDPConnectResponse {
     pub connection_id: u64,
 }
 
 #[derive(BinWrite, Debug)]
 #[binwrite(big)]
 struct UDPAnnounceRequest {
-    pub connection_id: u64,
-    pub action: u32,
-    pub transaction_id: u32,
     pub info_hash: Sha1Hash,
     pub peer_id: PeerID,
     pub downloaded: u64,
     pub left: u64,
     pub uploaded: u64,
-    pub event: u64,
+    pub event: u32,
     pub ip: u32,
     pub key: u32,
     pub num_want: i32,
     pub port: u16,
 }
 
+// Followed by the peers, in compact form: 6 bytes each for IPv4 or 18 bytes each for IPv6, as per BEP 15
 #[derive(BinRead, Debug)]
 #[br(big)]
-struct UDPPeer {
-    pub ip: u32,
-    pub port: u16,
-}
-
-#[derive(BinRead, Debug)]
-#[br(import(len: usize), big)]
 struct UDPAnnounceResponse {
-    pub action: u32,
-    pub transaction_id: u32,
-    pub _interval: u32,
-    pub _leechers: u32,
-    pub _seeders: u32,
+    pub interval: u32,
+    pub leechers: u32,
+    pub seeders: u32,
+}
 
-    #[br(count = len)]
-    pub peers: Vec<UDPPeer>,
+// The size of UDPAnnounceResponse
+const UDP_ANNOUNCE_RESPONSE_LENGTH: usize = 12;
+
+// Binds a socket for talking to a UDP tracker, and connects it to the tracker's address
+async fn udp_tracker_socket(url: &Url) -> Result<UdpSocket> {
+    let port = url.port().ok_or_else(|| anyhow!("URL has no port"))?;
+    // IPv6 addresses come bracketed in URLs, as in udp://[::1]:6969
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("URL has no host"))?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let addr = tokio::net::lookup_host((host, port))
+        .await?
+        .next()
+        .ok_or_else(|| anyhow!("Could not resolve {}", host))?;
+
+    let socket = match addr {
+        SocketAddr::V4(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?,
+        SocketAddr::V6(_) => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await?,
+    };
+    socket.connect(addr).await?;
+
+    Ok(socket)
 }
 
-async fn fetch_peers_udp(
-    url: Url,
-    metainfo: &Metainfo,
-    client_config: &ClientConfig,
-) -> HashSet<SocketAddr> {
-    let res: Result<_> = async {
-        let ip = url
-            .host()
-            .ok_or_else(|| anyhow!("URL has no host"))?
-            .to_string();
-        let port = url.port().ok_or_else(|| anyhow!("URL has no port"))?;
-        let addr = format!("{}:{}", ip, port);
-
-        // STEP ONE: request a connection ID from the tracker
-        // This is so the tracker knows that we do in fact have control over the IP in our
-        // UDP header (as UDP has no handshake process)
-        let transaction_id = rand::thread_rng().gen();
-        let connect_packet = UDPConnectRequest {
-            magic: 0x41727101980,
-            action: 0,
-            transaction_id,
+// Sends a single request and waits until the deadline for its response, returning the body of the response,
+// or None if it never came. Responses to other transactions, such as earlier ones we've since given up on, are skipped.
+async fn udp_transaction(
+    socket: &UdpSocket,
+    connection_id: u64,
+    action: u32,
+    transaction_id: u32,
+    body: &[u8],
+    deadline: Instant,
+) -> Result<Option<Vec<u8>>> {
+    let header = UDPRequestHeader {
+        connection_id,
+        action,
+        transaction_id,
+    };
+
+    let mut bytes = vec![];
+    header.write(&mut bytes)?;
+    bytes.extend(body);
+    socket.send(&bytes).await?;
+
+    let mut res = vec![0u8; UDP_MAX_PACKET_SIZE];
+    loop {
+        let res_length = match tokio::time::timeout_at(deadline.into(), socket.recv(&mut res)).await {
+            Ok(res_length) => res_length?,
+            Err(_) => return Ok(None),
         };
 
-        let mut bytes = vec![];
-        connect_packet.write(&mut bytes)?;
-
-        // TODO: IPv6 support
-        let socket = UdpSocket::bind("0.0.0.0:0").await?;
-        socket.connect(addr).await?;
-        socket.send(&bytes).await?;
-
-        let mut res = [0u8; 1024];
-        socket.recv(&mut res).await?;
-        let connect_reponse = UDPConnectResponse::read(&mut Cursor::new(res))?;
-
-        if connect_reponse.action != 0 || connect_reponse.transaction_id != transaction_id {
-            return Err(anyhow!(
-                "Invalid connection reponse packet received from {}: {:?}",
-                url,
-                connect_reponse
-            ));
+        let response_header = match UDPResponseHeader::read(&mut Cursor::new(&res[..res_length])) {
+            Ok(response_header) if response_header.transaction_id == transaction_id => response_header,
+            _ => continue,
         };
 
-        // STEP 2: make the actual announce request that results in us receiving a list of peers
-        let transaction_id = rand::thread_rng().gen();
-        let announce_packet = UDPAnnounceRequest {
-            connection_id: connect_reponse.connection_id,
-            action: 1, // announce
-            transaction_id,
-            info_hash: metainfo.info_hash,
-            peer_id: client_config.peer_id,
-            downloaded: 0,
-            left: metainfo.total_length as u64,
-            uploaded: 0,
-            event: 2, // started
-            ip: 0,
-            key: 0,
-            num_want: -1,
-            port: client_config.port,
+        let response_body = &res[UDP_RESPONSE_HEADER_LENGTH..res_length];
+        return match response_header.action {
+            UDP_ACTION_ERROR => Err(anyhow!("Tracker error: {}", String::from_utf8_lossy(response_body))),
+            response_action if response_action == action => Ok(Some(response_body.to_vec())),
+            response_action => Err(anyhow!("Unexpected action {} in response to action {}", response_action, action)),
         };
+    }
+}
 
-        let mut bytes = vec![];
-        announce_packet.write(&mut bytes)?;
-        socket.send(&bytes).await?;
-
-        let mut res = [0u8; 1024];
-        let res_length = socket.recv(&mut res).await?;
-        let num_peers = (res_length - 20) / 6;
-        let announce_reponse = UDPAnnounceResponse::read_args(&mut Cursor::new(res), (num_peers,))?;
-
-        if announce_reponse.action != 1 || announce_reponse.transaction_id != transaction_id {
-            return Err(anyhow!(
-                "Invalid connection reponse packet received from {}: {:?}",
-                url,
-                announce_reponse
-            ));
+// Sends a request to a UDP tracker, returning the body of its response. Before the request itself, we need a
+// connection ID from the tracker, so the tracker knows that we do in fact have control over the IP in our UDP header
+// (as UDP has no handshake process). Requests that go unanswered are sent again, with a fresh connection ID if
+// ours has expired in the meantime, up to the given number of times.
+async fn udp_request(socket: &UdpSocket, action: u32, body: &[u8], retransmissions: u32) -> Result<Vec<u8>> {
+    let addr = socket.peer_addr()?;
+    let connect_transaction_id = rand::thread_rng().gen();
+    let transaction_id = rand::thread_rng().gen();
+
+    for n in 0..=retransmissions {
+        let deadline = Instant::now() + UDP_BASE_TIMEOUT * 2u32.pow(n);
+
+        let cached_connection_id = UDP_CONNECTION_IDS
+            .lock()
+            .unwrap()
+            .get(&addr)
+            .filter(|(_, received)| received.elapsed() < UDP_CONNECTION_ID_LIFETIME)
+            .map(|(connection_id, _)| *connection_id);
+
+        let connection_id = match cached_connection_id {
+            Some(connection_id) => connection_id,
+            None => {
+                let Some(response) = udp_transaction(
+                    socket,
+                    UDP_PROTOCOL_ID,
+                    UDP_ACTION_CONNECT,
+                    connect_transaction_id,
+                    &[],
+                    deadline,
+                )
+                .await?
+                else {
+                    continue;
+                };
+
+                let connection_id = UDPConnectResponse::read(&mut Cursor::new(response))?.connection_id;
+                UDP_CONNECTION_IDS
+                    .lock()
+                    .unwrap()
+                    .insert(addr, (connection_id, Instant::now()));
+                connection_id
+            }
         };
 
-        Ok(announce_reponse
-            .peers
-            .into_iter()
-            .map(|peer| SocketAddr::V4(SocketAddrV4::new(peer.ip.into(), peer.port)))
-            .collect())
-    }
-    .await;
-
-    match res {
-        Ok(set) => set,
-        Err(e) => {
-            eprintln!("Failed to retrive peers from tracker {}: {}", url, e);
-            HashSet::new()
+        match udp_transaction(socket, connection_id, action, transaction_id, body, deadline).await {
+            Ok(Some(response)) => return Ok(response),
+            Ok(None) => continue,
+            Err(e) => {
+                // The error may well be down to our connection ID, so we'll get a new one next time
+                UDP_CONNECTION_IDS.lock().unwrap().remove(&addr);
+                return Err(e);
+            }
         }
     }
+
+    Err(anyhow!("Timed out"))
 }
 
-async fn fetch_peers(
+async fn announce_udp(
     url: &Url,
-    metainfo: &Metainfo,
+    request: &AnnounceRequest<'_>,
     client_config: &ClientConfig,
-) -> HashSet<SocketAddr> {
+) -> Result<AnnounceResponse> {
+    let socket = udp_tracker_socket(url).await?;
+
+    let announce_packet = UDPAnnounceRequest {
+        info_hash: *request.info_hash,
+        peer_id: client_config.peer_id,
+        downloaded: request.downloaded,
+        left: request.left,
+        uploaded: request.uploaded,
+        event: request.event.udp_id(),
+        ip: 0,
+        key: 0,
+        num_want: -1,
+        port: client_config.port,
+    };
+
+    let mut bytes = vec![];
+    announce_packet.write(&mut bytes)?;
+
+    // We only announce that we've stopped as we're exiting, so it isn't worth holding that up to try again
+    let retransmissions = match request.event {
+        AnnounceEvent::Stopped => 0,
+        _ => client_config.udp_retransmissions,
+    };
+
+    let res = udp_request(&socket, UDP_ACTION_ANNOUNCE, &bytes, retransmissions).await?;
+    let announce_reponse = UDPAnnounceResponse::read(&mut Cursor::new(&res))?;
+
+    // Trackers reply with peers of the same address family as the announce itself
+    Ok(AnnounceResponse {
+        peers: parse_compact_peers(&res[UDP_ANNOUNCE_RESPONSE_LENGTH..], socket.peer_addr()?.is_ipv6())
+            .into_iter()
+            .collect(),
+        interval: Some(Duration::from_secs(announce_reponse.interval as u64)),
+        // UDP trackers have no separate minimum interval
+        min_interval: None,
+        seeders: Some(announce_reponse.seeders),
+        leechers: Some(announce_reponse.leechers),
+        warning: None,
+        tracker_id: None,
+    })
+}
+
+// Announces to a single tracker. HTTP trackers are given up on if they take longer than the configured timeout,
+// while UDP trackers have their own schedule for retrying requests.
+pub async fn announce(url: &Url, request: &AnnounceRequest<'_>, client_config: &ClientConfig) -> Result<AnnounceResponse> {
     match url.scheme() {
-        "http" | "https" => fetch_peers_http(url.clone(), metainfo, client_config).await,
-        "udp" => fetch_peers_udp(url.clone(), metainfo, client_config).await,
-        _ => {
-            async {
-                eprintln!(
-                    "Unknown protocol {} in tracker URL {}; skipping.",
-                    url.scheme(),
-                    url.as_str()
-                );
-                HashSet::new()
-            }
+        "http" | "https" => tokio::time::timeout(client_config.timeout, announce_http(url.clone(), request, client_config))
             .await
-        }
+            .map_err(|_| anyhow!("Timed out"))?,
+        "udp" => announce_udp(url, request, client_config).await,
+        scheme => Err(anyhow!("Unknown protocol {} in tracker URL", scheme)),
     }
 }
 
-impl PeerList {
-    pub async fn fetch_peers_from_metainfo(
-        metainfo: &Metainfo,
-        client_config: &ClientConfig,
-    ) -> Self {
-        let mut tracker_peer_futures = Vec::new();
-
-        for url in &metainfo.announce_list {
-            // TODO: retry connection instead of just giving up after one failed attempt
-            tracker_peer_futures.push(tokio::time::timeout(
-                client_config.timeout,
-                fetch_peers(url, metainfo, client_config),
-            ));
-        }
+// A tracker's counts of the peers in a torrent's swarm
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct ScrapeStats {
+    #[serde(rename(deserialize = "complete"))]
+    pub seeders: u32,
+    #[serde(rename(deserialize = "incomplete"))]
+    pub leechers: u32,
+    // The number of times the torrent has been downloaded in full
+    #[serde(rename(deserialize = "downloaded"))]
+    pub completed: u32,
+}
 
-        let tracker_peer_sets: Vec<_> = futures::future::join_all(tracker_peer_futures)
-            .await
-            .into_iter()
-            .filter_map(|r| r.ok())
-            .collect();
+// Trackers that support scraping do so at the URL formed by replacing "announce" with "scrape" at the start of the
+// last part of their announce URL's path (BEP 48). Anything else can't be scraped.
+fn http_scrape_url(announce_url: &Url) -> Option<Url> {
+    let path = announce_url.path();
+    let (directory, last) = path.rsplit_once('/')?;
+    let rest = last.strip_prefix("announce")?;
 
-        let mut peers = HashSet::new();
+    let mut scrape_url = announce_url.clone();
+    scrape_url.set_path(&format!("{}/scrape{}", directory, rest));
+    Some(scrape_url)
+}
 
-        for tracker_peer_set in tracker_peer_sets {
-            peers.extend(tracker_peer_set);
-        }
+#[derive(Deserialize)]
+struct HttpScrapeResponse {
+    // Keyed by info hash
+    files: HashMap<ByteBuf, ScrapeStats>,
+}
+
+async fn scrape_http(url: &Url, info_hashes: &[Sha1Hash]) -> Result<HashMap<Sha1Hash, ScrapeStats>> {
+    let mut url = http_scrape_url(url).ok_or_else(|| anyhow!("Tracker doesn't support scraping"))?;
+
+    // As with announces, the info hashes have to be encoded by hand, and any existing query (such as a passkey) kept
+    let mut query: Vec<String> = url.query().into_iter().map(str::to_string).collect();
+    query.extend(
+        info_hashes
+            .iter()
+            .map(|info_hash| format!("info_hash={}", urlencoding::encode_binary(info_hash))),
+    );
+    url.set_query(Some(&query.join("&")));
+
+    let res = http_tracker_request(url).await?;
+    let response: HttpScrapeResponse = bencode::from_bytes(&res)?;
+
+    Ok(response
+        .files
+        .into_iter()
+        .filter_map(|(info_hash, stats)| Some((Sha1Hash::try_from(info_hash.as_slice()).ok()?, stats)))
+        .collect())
+}
+
+// BEP 15 caps the number of info hashes in one scrape at about 74, so that the request fits in a single packet
+const MAX_UDP_SCRAPE_INFO_HASHES: usize = 74;
+
+// The response holds the seeders, completed and leechers counts of each info hash, in the order they were asked for
+async fn scrape_udp(url: &Url, info_hashes: &[Sha1Hash], retransmissions: u32) -> Result<HashMap<Sha1Hash, ScrapeStats>> {
+    let socket = udp_tracker_socket(url).await?;
+    let mut scrapes = HashMap::new();
+
+    for chunk in info_hashes.chunks(MAX_UDP_SCRAPE_INFO_HASHES) {
+        let res = udp_request(&socket, UDP_ACTION_SCRAPE, &chunk.concat(), retransmissions).await?;
 
-        Self(peers)
+        let counts = res.chunks_exact(12).map(|chunk| {
+            let count = |i: usize| u32::from_be_bytes(chunk[i * 4..i * 4 + 4].try_into().unwrap());
+            ScrapeStats {
+                seeders: count(0),
+                completed: count(1),
+                leechers: count(2),
+            }
+        });
+
+        scrapes.extend(chunk.iter().copied().zip(counts));
+    }
+
+    Ok(scrapes)
+}
+
+// Asks a tracker for its counts of the peers in each of the given torrents' swarms, with the same timeouts as announces.
+// Torrents the tracker doesn't know about are left out of the results.
+pub async fn scrape(
+    url: &Url,
+    info_hashes: &[Sha1Hash],
+    timeout: Duration,
+    udp_retransmissions: u32,
+) -> Result<HashMap<Sha1Hash, ScrapeStats>> {
+    match url.scheme() {
+        "http" | "https" => tokio::time::timeout(timeout, scrape_http(url, info_hashes))
+            .await
+            .map_err(|_| anyhow!("Timed out"))?,
+        "udp" => scrape_udp(url, info_hashes, udp_retransmissions).await,
+        scheme => Err(anyhow!("Unknown protocol {} in tracker URL", scheme)),
+    }
+}
+
+// Looks up the torrent's peers on the DHT, announcing ourselves as one of them
+pub async fn fetch_peers_from_dht(dht: &Dht, info_hash: &Sha1Hash, client_config: &ClientConfig) -> HashSet<SocketAddr> {
+    // Lookups end by themselves once they stop getting any closer to the info hash, but a slow DHT shouldn't hold up the download
+    match tokio::time::timeout(DHT_LOOKUP_TIMEOUT, dht.find_peers(info_hash, Some(client_config.port))).await {
+        Ok(peers) => peers,
+        Err(_) => {
+            eprintln!("DHT lookup timed out");
+            HashSet::new()
+        }
     }
 }
@@ -0,0 +1,144 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use tokio::sync::{mpsc, watch};
+
+use crate::{
+    bencode,
+    extensions::Extension,
+    peer_list::{encode_compact_peer, parse_compact_peers},
+};
+
+// BEP 11 asks that PEX messages be sent at most once a minute, with at most 50 added and 50 dropped peers in each
+const PEX_INTERVAL: Duration = Duration::from_secs(60);
+const MAX_PEERS_PER_MESSAGE: usize = 50;
+
+// Flags describing a peer in a PEX message
+pub const PEX_FLAG_SEED: u8 = 0x02;
+// We've connected to the peer ourselves, so it's reachable
+pub const PEX_FLAG_REACHABLE: u8 = 0x10;
+
+// Peers are given in compact form, each with one byte of flags in the matching .f string
+#[derive(Serialize, Deserialize, Default)]
+struct PexMessage {
+    #[serde(default)]
+    added: ByteBuf,
+    #[serde(rename = "added.f", default)]
+    added_flags: ByteBuf,
+    #[serde(default)]
+    added6: ByteBuf,
+    #[serde(rename = "added6.f", default)]
+    added6_flags: ByteBuf,
+    #[serde(default)]
+    dropped: ByteBuf,
+    #[serde(default)]
+    dropped6: ByteBuf,
+}
+
+// The ut_pex extension, which swaps lists of connected peers with the peer on the other end of the connection.
+// connected_peers holds the peers we're connected to and their flags, as kept up to date by the downloader.
+pub struct PeerExchange {
+    peer: SocketAddr,
+    connected_peers: watch::Receiver<HashMap<SocketAddr, u8>>,
+    discovered_peers: mpsc::UnboundedSender<SocketAddr>,
+    // The peers we've told this peer we're connected to
+    sent: HashSet<SocketAddr>,
+    last_sent: Option<Instant>,
+}
+
+impl PeerExchange {
+    pub fn new(
+        peer: SocketAddr,
+        connected_peers: watch::Receiver<HashMap<SocketAddr, u8>>,
+        discovered_peers: mpsc::UnboundedSender<SocketAddr>,
+    ) -> Self {
+        Self {
+            peer,
+            connected_peers,
+            discovered_peers,
+            sent: HashSet::new(),
+            last_sent: None,
+        }
+    }
+}
+
+impl Extension for PeerExchange {
+    fn name(&self) -> &'static str {
+        "ut_pex"
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let message: PexMessage = bencode::from_bytes(payload)?;
+
+        // Dropped peers are of no interest; a peer that's gone from one swarm member may still be reachable by us
+        for peer in parse_compact_peers(&message.added, false)
+            .into_iter()
+            .chain(parse_compact_peers(&message.added6, true))
+        {
+            if peer != self.peer && self.discovered_peers.send(peer).is_err() {
+                // The downloader has gone away, so there's no one left to tell
+                break;
+            }
+        }
+
+        Ok(Vec::new())
+    }
+
+    fn on_tick(&mut self) -> Result<Vec<Vec<u8>>> {
+        if self.last_sent.is_some_and(|last_sent| last_sent.elapsed() < PEX_INTERVAL) {
+            return Ok(Vec::new());
+        }
+
+        let connected_peers = self.connected_peers.borrow().clone();
+
+        let added: Vec<(SocketAddr, u8)> = connected_peers
+            .iter()
+            .filter(|(peer, _)| **peer != self.peer && !self.sent.contains(peer))
+            .take(MAX_PEERS_PER_MESSAGE)
+            .map(|(peer, flags)| (*peer, *flags))
+            .collect();
+
+        let dropped: Vec<SocketAddr> = self
+            .sent
+            .iter()
+            .filter(|peer| !connected_peers.contains_key(peer))
+            .take(MAX_PEERS_PER_MESSAGE)
+            .copied()
+            .collect();
+
+        if added.is_empty() && dropped.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut message = PexMessage::default();
+
+        for (peer, flags) in &added {
+            if peer.is_ipv4() {
+                encode_compact_peer(peer, &mut message.added);
+                message.added_flags.push(*flags);
+            } else {
+                encode_compact_peer(peer, &mut message.added6);
+                message.added6_flags.push(*flags);
+            }
+            self.sent.insert(*peer);
+        }
+
+        for peer in &dropped {
+            if peer.is_ipv4() {
+                encode_compact_peer(peer, &mut message.dropped);
+            } else {
+                encode_compact_peer(peer, &mut message.dropped6);
+            }
+            self.sent.remove(peer);
+        }
+
+        self.last_sent = Some(Instant::now());
+        Ok(vec![bencode::to_bytes(&message)?])
+    }
+}
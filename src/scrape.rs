@@ -0,0 +1,159 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use anyhow::Result;
+use data_encoding::HEXLOWER;
+use reqwest::Url;
+use serde::Serialize;
+
+use crate::{
+    magnet::MagnetLink,
+    metainfo::{Info, Metainfo, Sha1Hash},
+    peer_list::{self, ScrapeStats},
+};
+
+// A torrent to be scraped, as given on the command line
+struct Torrent {
+    name: Option<String>,
+    info_hash: Sha1Hash,
+    trackers: Vec<Url>,
+}
+
+impl Torrent {
+    // Reads the torrent from a metainfo file, or a magnet link
+    fn load(torrent: &str) -> Result<Self> {
+        if torrent.starts_with("magnet:") {
+            let magnet = MagnetLink::parse(torrent)?;
+
+            Ok(Self {
+                name: magnet.display_name,
+                info_hash: magnet.info_hash,
+                trackers: magnet.trackers,
+            })
+        } else {
+            let metainfo = Metainfo::from_file(torrent)?;
+
+            // The same tracker may turn up in more than one tier
+            let mut trackers: Vec<Url> = Vec::new();
+            for tracker in metainfo.announce_list.into_iter().flatten() {
+                if !trackers.contains(&tracker) {
+                    trackers.push(tracker);
+                }
+            }
+
+            Ok(Self {
+                name: Some(match metainfo.info {
+                    Info::SingleFile(file_info) => file_info.name,
+                    Info::Directory(files_info) => files_info.name,
+                }),
+                info_hash: metainfo.info_hash,
+                trackers,
+            })
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ScrapeOutput {
+    name: Option<String>,
+    info_hash: String,
+    trackers: Vec<TrackerOutput>,
+}
+
+#[derive(Serialize)]
+struct TrackerOutput {
+    url: String,
+    #[serde(flatten)]
+    stats: Option<ScrapeStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+fn plural(count: u32, word: &str) -> String {
+    format!("{} {}{}", count, word, if count == 1 { "" } else { "s" })
+}
+
+fn print_human(output: &[ScrapeOutput]) {
+    for (i, torrent) in output.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+
+        println!("{} ({})", torrent.name.as_deref().unwrap_or("Unnamed torrent"), torrent.info_hash);
+
+        if torrent.trackers.is_empty() {
+            println!("  No trackers");
+        }
+
+        for tracker in &torrent.trackers {
+            match (&tracker.stats, &tracker.error) {
+                (Some(stats), _) => println!(
+                    "  {}: {}, {}, {} completed",
+                    tracker.url,
+                    plural(stats.seeders, "seeder"),
+                    plural(stats.leechers, "leecher"),
+                    stats.completed
+                ),
+                (None, error) => println!("  {}: {}", tracker.url, error.as_deref().unwrap_or_default()),
+            }
+        }
+    }
+}
+
+// Asks each torrent's trackers how many seeders and leechers it has, without announcing to them.
+// Torrents sharing a tracker are scraped with a single request to it.
+pub async fn scrape(torrents: &[String], timeout: Duration, udp_retransmissions: u32, json: bool) -> Result<()> {
+    let torrents = torrents
+        .iter()
+        .map(|torrent| Torrent::load(torrent))
+        .collect::<Result<Vec<Torrent>>>()?;
+
+    let mut info_hashes_by_tracker: BTreeMap<&Url, Vec<Sha1Hash>> = BTreeMap::new();
+    for torrent in &torrents {
+        for tracker in &torrent.trackers {
+            info_hashes_by_tracker.entry(tracker).or_default().push(torrent.info_hash);
+        }
+    }
+
+    let scrapes = futures::future::join_all(
+        info_hashes_by_tracker
+            .iter()
+            .map(|(tracker, info_hashes)| peer_list::scrape(tracker, info_hashes, timeout, udp_retransmissions)),
+    )
+    .await;
+    let scrapes: BTreeMap<&Url, _> = info_hashes_by_tracker.into_keys().zip(scrapes).collect();
+
+    let output: Vec<ScrapeOutput> = torrents
+        .iter()
+        .map(|torrent| ScrapeOutput {
+            name: torrent.name.clone(),
+            info_hash: HEXLOWER.encode(&torrent.info_hash),
+            trackers: torrent
+                .trackers
+                .iter()
+                .map(|tracker| {
+                    let (stats, error) = match &scrapes[tracker] {
+                        Ok(scrape) => match scrape.get(&torrent.info_hash) {
+                            Some(stats) => (Some(*stats), None),
+                            None => (None, Some("Torrent not known to tracker".to_string())),
+                        },
+                        Err(e) => (None, Some(e.to_string())),
+                    };
+
+                    TrackerOutput {
+                        url: tracker.to_string(),
+                        stats,
+                        error,
+                    }
+                })
+                .collect(),
+        })
+        .collect();
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&output)?);
+    } else {
+        print_human(&output);
+    }
+
+    Ok(())
+}